@@ -0,0 +1,666 @@
+//! Static checks over parsed programs.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Display, Formatter, Write};
+
+use super::parser::HEART_MARKS;
+use super::structure::{HeartTree, Instruction, Operation, OperationType, Span};
+
+/// A heart in an instruction's heart tree, and where it could jump.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JumpInfo {
+    /// Position of the instruction in the program.
+    pub position: usize,
+    /// `hangul_times_dots` of the instruction.
+    pub param: u64,
+    pub id: u64,
+    /// Other instructions with a heart of the same `(param, id)`, any of which may become the
+    /// label for it at runtime.
+    pub targets: Vec<usize>,
+}
+
+impl JumpInfo {
+    /// Whether resolving to this heart could ever jump. If not, the heart does nothing.
+    pub fn can_jump(&self) -> bool {
+        !self.targets.is_empty()
+    }
+}
+
+fn collect_hearts(tree: &HeartTree, ids: &mut Vec<u64>) {
+    match tree {
+        HeartTree::Heart(id) => {
+            if !ids.contains(id) {
+                ids.push(*id);
+            }
+        }
+        HeartTree::LessThan(l, r) | HeartTree::Equals(l, r) => {
+            collect_hearts(l, ids);
+            collect_hearts(r, ids);
+        }
+        HeartTree::Return | HeartTree::Nil => {}
+    }
+}
+
+/// Lists every heart in `instructions` with its possible jump targets, in program order.
+///
+/// A label is the first instruction to resolve to a `(param, id)` pair at runtime, and
+/// execution wraps around at the end, so any other instruction sharing the pair is a possible
+/// target regardless of where it is.
+pub fn jump_targets(instructions: &[Instruction]) -> Vec<JumpInfo> {
+    let mut hearts = vec![];
+    let mut positions = HashMap::<(u64, u64), Vec<usize>>::new();
+    let mut ids = vec![];
+    for (position, instr) in instructions.iter().enumerate() {
+        ids.clear();
+        collect_hearts(instr.heart_tree(), &mut ids);
+        let param = instr.hangul_times_dots();
+        for &id in &ids {
+            positions.entry((param, id)).or_default().push(position);
+            hearts.push((position, param, id));
+        }
+    }
+    hearts
+        .into_iter()
+        .map(|(position, param, id)| JumpInfo {
+            position,
+            param,
+            id,
+            targets: positions[&(param, id)]
+                .iter()
+                .copied()
+                .filter(|&p| p != position)
+                .collect(),
+        })
+        .collect()
+}
+
+/// Where execution may go after an instruction.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CfgEdge {
+    /// To the next instruction, wrapping around to the first one at the end.
+    Fallthrough(usize),
+    /// To the label of a heart.
+    Jump(usize),
+    /// Back to an instruction which made a jump, on a return heart.
+    Return(usize),
+}
+
+/// An instruction in the control flow graph of a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CfgNode {
+    pub position: usize,
+    pub operation: OperationType,
+    /// Outgoing edges, jumps first and fallthrough last, without duplicates.
+    pub edges: Vec<CfgEdge>,
+}
+
+fn visit_leaves(tree: &HeartTree, f: &mut impl FnMut(&HeartTree)) {
+    match tree {
+        HeartTree::LessThan(l, r) | HeartTree::Equals(l, r) => {
+            visit_leaves(l, f);
+            visit_leaves(r, f);
+        }
+        leaf => f(leaf),
+    }
+}
+
+/// Builds the control flow graph of `instructions`, one node per instruction in program order.
+///
+/// Labels are recorded as if the program ran straight through from the start, so the first
+/// instruction with a `(param, id)` pair is its label; `jump_targets` lists every label which
+/// may be chosen at runtime instead. A return goes back to the last instruction which jumped,
+/// which is only known at runtime, so return edges lead to every instruction which can jump
+/// and are an over-approximation.
+pub fn control_flow(instructions: &[Instruction]) -> Vec<CfgNode> {
+    let mut labels = HashMap::<(u64, u64), usize>::new();
+    for (position, instr) in instructions.iter().enumerate() {
+        let param = instr.hangul_times_dots();
+        visit_leaves(instr.heart_tree(), &mut |leaf| {
+            if let HeartTree::Heart(id) = leaf {
+                labels.entry((param, *id)).or_insert(position);
+            }
+        });
+    }
+
+    let mut nodes = instructions
+        .iter()
+        .enumerate()
+        .map(|(position, instr)| {
+            let param = instr.hangul_times_dots();
+            let mut edges = vec![];
+            let mut falls_through = false;
+            visit_leaves(instr.heart_tree(), &mut |leaf| match leaf {
+                HeartTree::Heart(id) => match labels[&(param, *id)] {
+                    label if label == position => falls_through = true,
+                    label => edges.push(CfgEdge::Jump(label)),
+                },
+                // Return edges are filled in below; a return with nowhere to go falls through
+                HeartTree::Return | HeartTree::Nil => falls_through = true,
+                _ => unreachable!(),
+            });
+            if falls_through {
+                edges.push(CfgEdge::Fallthrough((position + 1) % instructions.len()));
+            }
+            CfgNode {
+                position,
+                operation: instr.operation_type(),
+                edges,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let jumping = nodes
+        .iter()
+        .filter(|node| node.edges.iter().any(|e| matches!(e, CfgEdge::Jump(_))))
+        .map(|node| node.position)
+        .collect::<Vec<_>>();
+    for (node, instr) in nodes.iter_mut().zip(instructions) {
+        let mut returns = false;
+        visit_leaves(instr.heart_tree(), &mut |leaf| {
+            returns |= *leaf == HeartTree::Return;
+        });
+        if returns {
+            let at = node.edges.len() - 1;
+            node.edges
+                .splice(at..at, jumping.iter().map(|&p| CfgEdge::Return(p)));
+        }
+        let mut seen = vec![];
+        node.edges.retain(|edge| {
+            let new = !seen.contains(edge);
+            seen.push(*edge);
+            new
+        });
+    }
+    nodes
+}
+
+/// Number of instructions of each operation type, indexed by `op as usize` like
+/// `OperationType::ALL`.
+pub fn opcode_histogram(instructions: &[Instruction]) -> [usize; 6] {
+    let mut counts = [0; OperationType::ALL.len()];
+    for instr in instructions {
+        counts[instr.operation_type() as usize] += 1;
+    }
+    counts
+}
+
+/// Folds pushes of constants which are then summed or multiplied into a single push of the
+/// result, without changing what the program does.
+///
+/// Only instructions with no hearts are folded. Those can't be jumped to, so they're always
+/// entered from the instruction before them, and the selected stack is known if a `흑` comes
+/// before them. Folding is skipped while stdout or stderr is selected, where pushing prints and
+/// other operations end the program, and when a result wouldn't fit in a push.
+pub fn fold_constants(instructions: Vec<Instruction>) -> Vec<Instruction> {
+    let mut output = Vec::<Option<Instruction>>::with_capacity(instructions.len());
+    // Positions in `output` of the pushes which are still on top of the selected stack, with
+    // their values
+    let mut constants = Vec::<(usize, i64)>::new();
+    let mut selected = None;
+    for (position, instr) in instructions.into_iter().enumerate() {
+        if position == 0 || !instr.heart_tree().is_nil() {
+            // Might be entered with anything selected, by a jump or by wrapping around
+            selected = None;
+        }
+        let safe = !matches!(selected, None | Some(1) | Some(2)) && instr.heart_tree().is_nil();
+        let count = instr.hangul_count();
+        let to = instr.dots();
+        match instr.operation_type() {
+            OperationType::Push if safe => {
+                let value = count.checked_mul(to).and_then(|v| i64::try_from(v).ok());
+                match value {
+                    Some(value) => constants.push((output.len(), value)),
+                    None => constants.clear(),
+                }
+                output.push(Some(instr));
+                continue;
+            }
+            op @ (OperationType::Add | OperationType::Multiply)
+                if safe && count as usize <= constants.len() =>
+            {
+                let operands = &constants[constants.len() - count as usize..];
+                let result = if op == OperationType::Add {
+                    operands
+                        .iter()
+                        .try_fold(0i64, |a, &(_, v)| a.checked_add(v))
+                } else {
+                    operands
+                        .iter()
+                        .try_fold(1i64, |a, &(_, v)| a.checked_mul(v))
+                };
+                if let Some(result) = result {
+                    for &(idx, _) in operands {
+                        output[idx] = None;
+                    }
+                    constants.truncate(constants.len() - count as usize);
+                    let push = Operation::from_chars('형', None, 1);
+                    if Some(to) == selected {
+                        constants.push((output.len(), result));
+                    }
+                    output.push(Some(Instruction::new(push, result as u64, HeartTree::Nil)));
+                    if Some(to) != selected {
+                        let add = Operation::from_chars('항', None, 1);
+                        output.push(Some(Instruction::new(add, to, HeartTree::Nil)));
+                    }
+                    continue;
+                }
+            }
+            OperationType::Duplicate => {
+                selected = Some(to);
+            }
+            _ => {}
+        }
+        constants.clear();
+        output.push(Some(instr));
+    }
+    output.into_iter().flatten().collect()
+}
+
+/// Every stack id `instructions` may touch: the starting stack 3, and the target of each
+/// instruction other than push, which is its number of dots.
+///
+/// Targets are always literal, and push only touches a stack selected by some `흑`, so this
+/// is complete. It's an over-approximation, since some instructions may never run. IO stacks
+/// are included if they are targeted.
+pub fn referenced_stacks(instructions: &[Instruction]) -> BTreeSet<u64> {
+    let targets = instructions
+        .iter()
+        .filter(|instr| instr.operation_type() != OperationType::Push)
+        .map(Instruction::dots);
+    std::iter::once(3).chain(targets).collect()
+}
+
+// Writes the nodes and edges of `tree` as node `i{position}_{id}`, numbering its subtrees from
+// `next` on
+fn write_dot_tree(
+    tree: &HeartTree,
+    position: usize,
+    id: usize,
+    next: &mut usize,
+    dot: &mut String,
+) {
+    let label = match tree {
+        HeartTree::Heart(id) => match HEART_MARKS.get(*id as usize) {
+            Some(mark) => mark.to_string(),
+            None => format!("heart {}", id),
+        },
+        HeartTree::Return => "♡".to_owned(),
+        HeartTree::LessThan(..) => "<".to_owned(),
+        HeartTree::Equals(..) => "=".to_owned(),
+        HeartTree::Nil => "nil".to_owned(),
+    };
+    writeln!(dot, "  i{}_{} [label=\"{}\"];", position, id, label).unwrap();
+    if let HeartTree::LessThan(l, r) | HeartTree::Equals(l, r) = tree {
+        for (subtree, taken) in [(l, "yes"), (r, "no")] {
+            let child = *next;
+            *next += 1;
+            writeln!(
+                dot,
+                "  i{0}_{1} -> i{0}_{2} [label=\"{3}\"];",
+                position, id, child, taken
+            )
+            .unwrap();
+            write_dot_tree(subtree, position, child, next, dot);
+        }
+    }
+}
+
+/// Describes the heart tree of each instruction as a Graphviz graph, for `dot` to draw.
+///
+/// Each instruction is a box labeled with its position and source, pointing to the root of its
+/// heart tree unless the tree is empty. Comparisons point to the branch taken when they hold
+/// with `yes`, and to the other one with `no`.
+pub fn to_dot(instructions: &[Instruction]) -> String {
+    let mut dot = String::from("digraph hearts {\n");
+    for (position, instr) in instructions.iter().enumerate() {
+        let source = instr.to_source().unwrap_or_else(|| format!("{:?}", instr));
+        writeln!(
+            dot,
+            "  i{} [shape=box, label=\"[{}] {}\"];",
+            position, position, source
+        )
+        .unwrap();
+        if !instr.heart_tree().is_nil() {
+            writeln!(dot, "  i{0} -> i{0}_0;", position).unwrap();
+            write_dot_tree(instr.heart_tree(), position, 0, &mut 1, &mut dot);
+        }
+    }
+    dot.push_str("}\n");
+    dot
+}
+
+fn describe_stack(id: u64) -> String {
+    match id {
+        0 => "stack 0 (input)".to_owned(),
+        1 => "stack 1 (output)".to_owned(),
+        2 => "stack 2 (error output)".to_owned(),
+        id => format!("stack {}", id),
+    }
+}
+
+fn describe_hearts(tree: &HeartTree, param: u64) -> String {
+    match tree {
+        HeartTree::Heart(id) => match HEART_MARKS.get(*id as usize) {
+            Some(mark) => format!("jump to the label for {} at {}", mark, param),
+            None => format!("jump to the label for heart {} at {}", id, param),
+        },
+        HeartTree::Return => "return to the last jump".to_owned(),
+        HeartTree::Nil => "do nothing".to_owned(),
+        HeartTree::LessThan(l, r) => format!(
+            "pop a value; if it is less than {}, {}, otherwise {}",
+            param,
+            describe_hearts(l, param),
+            describe_hearts(r, param)
+        ),
+        HeartTree::Equals(l, r) => format!(
+            "pop a value; if it equals {}, {}, otherwise {}",
+            param,
+            describe_hearts(l, param),
+            describe_hearts(r, param)
+        ),
+    }
+}
+
+/// Describes what `instr` does in plain English, e.g. "push 6 onto the selected stack".
+///
+/// Stacks are named as in the default layout. The description leaves out that any operation
+/// but a push ends the program while stdout or stderr is selected.
+pub fn explain(instr: &Instruction) -> String {
+    let count = instr.hangul_count();
+    let to = describe_stack(instr.dots());
+    let mut explanation = match (instr.operation_type(), count) {
+        (OperationType::Push, _) => {
+            format!("push {} onto the selected stack", instr.hangul_times_dots())
+        }
+        (OperationType::Add | OperationType::Multiply, 1) => {
+            format!("move the top value onto {}", to)
+        }
+        (OperationType::Add, count) => {
+            format!(
+                "pop the top {} values and push their sum onto {}",
+                count, to
+            )
+        }
+        (OperationType::Multiply, count) => {
+            format!(
+                "pop the top {} values and push their product onto {}",
+                count, to
+            )
+        }
+        (OperationType::Negate, 1) => {
+            format!("negate the top value and push a copy onto {}", to)
+        }
+        (OperationType::Negate, count) => format!(
+            "negate the top {} values and push the sum of the negations onto {}",
+            count, to
+        ),
+        (OperationType::Reciprocate, 1) => {
+            format!(
+                "replace the top value with its reciprocal and push a copy onto {}",
+                to
+            )
+        }
+        (OperationType::Reciprocate, count) => format!(
+            "replace the top {} values with their reciprocals and push the product of those \
+             onto {}",
+            count, to
+        ),
+        (OperationType::Duplicate, 1) => format!("copy the top value onto {}, then select it", to),
+        (OperationType::Duplicate, count) => {
+            format!(
+                "copy the top value {} times onto {}, then select it",
+                count, to
+            )
+        }
+    };
+    if !instr.heart_tree().is_nil() {
+        explanation.push_str("; then ");
+        explanation.push_str(&describe_hearts(
+            instr.heart_tree(),
+            instr.hangul_times_dots(),
+        ));
+    }
+    explanation
+}
+
+/// Something suspicious in a program. These are advisory; the program still runs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LintKind {
+    /// A push without dots, which always pushes zero whatever its hangul count is.
+    ZeroDotPush,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lint {
+    pub kind: LintKind,
+    /// Position of the instruction in the program.
+    pub position: usize,
+    /// Where the instruction is in the source, if it was parsed from one.
+    pub span: Option<Span>,
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.kind {
+            LintKind::ZeroDotPush => write!(
+                f,
+                "instruction {} is a push without dots, which always pushes zero",
+                self.position
+            ),
+        }
+    }
+}
+
+/// Finds suspicious instructions, in program order.
+pub fn lint(instructions: &[Instruction]) -> Vec<Lint> {
+    instructions
+        .iter()
+        .enumerate()
+        .filter(|(_, instr)| instr.operation_type() == OperationType::Push && instr.dots() == 0)
+        .map(|(position, instr)| Lint {
+            kind: LintKind::ZeroDotPush,
+            position,
+            span: instr.span().cloned(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::Parser;
+    use super::super::processor::Processor;
+    use super::super::stack::{HyeongReadStack, HyeongWriteStack, StackManager};
+    use super::super::structure::Instruction;
+    use super::{
+        control_flow, explain, fold_constants, jump_targets, lint, opcode_histogram,
+        referenced_stacks, to_dot, CfgEdge, JumpInfo, Lint, LintKind,
+    };
+
+    fn run(instructions: Vec<Instruction>) -> (isize, Vec<u8>, Vec<u8>) {
+        let mut output = vec![];
+        let mut error = vec![];
+        let (exit_code, err) = {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(&mut output);
+            let stderr = HyeongWriteStack::new(&mut error);
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            Processor::with_stack_manager(instructions.into_iter(), stacks).run()
+        };
+        err.unwrap();
+        (exit_code, output, error)
+    }
+
+    fn assert_folds_to(source: &str, len: usize) {
+        let folded = fold_constants(Parser::new(source).collect());
+        assert_eq!(folded.len(), len);
+        assert_eq!(run(folded), run(Parser::new(source).collect()));
+    }
+
+    #[test]
+    fn jump_targets_fibonacci() {
+        let source = include_str!("../snippets/fibonacci.hyeong");
+        let instructions = Parser::new(source).collect::<Vec<_>>();
+        let hearts = jump_targets(&instructions);
+        assert!(!hearts.is_empty());
+        assert!(hearts.iter().any(JumpInfo::can_jump));
+    }
+
+    #[test]
+    fn jump_targets_simple() {
+        // The first and third instructions share (3, 0); the second is alone with (2, 0)
+        let instructions = Parser::new("형...♥ 하앙.♥ 흑...♥").collect::<Vec<_>>();
+        let hearts = jump_targets(&instructions);
+        assert_eq!(
+            hearts,
+            vec![
+                JumpInfo {
+                    position: 0,
+                    param: 3,
+                    id: 0,
+                    targets: vec![2],
+                },
+                JumpInfo {
+                    position: 1,
+                    param: 2,
+                    id: 0,
+                    targets: vec![],
+                },
+                JumpInfo {
+                    position: 2,
+                    param: 3,
+                    id: 0,
+                    targets: vec![0],
+                },
+            ]
+        );
+        assert!(!hearts[1].can_jump());
+    }
+
+    #[test]
+    fn control_flow_edges() {
+        // The third instruction jumps back to the first, and the fourth may return there
+        let instructions = Parser::new("형...♥ 하앙.♥ 흑...♥ 흑♡♥?♥").collect::<Vec<_>>();
+        let edges = control_flow(&instructions)
+            .into_iter()
+            .map(|node| node.edges)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            edges,
+            vec![
+                vec![CfgEdge::Fallthrough(1)],
+                vec![CfgEdge::Fallthrough(2)],
+                vec![CfgEdge::Jump(0)],
+                vec![CfgEdge::Return(2), CfgEdge::Fallthrough(0)],
+            ]
+        );
+        assert!(control_flow(&[]).is_empty());
+    }
+
+    #[test]
+    fn opcode_histogram_counts() {
+        let instructions = Parser::new("형 형 하앙. 흐읍 흑 흑 흑").collect::<Vec<_>>();
+        assert_eq!(opcode_histogram(&instructions), [2, 1, 0, 0, 1, 3]);
+        assert_eq!(opcode_histogram(&[]), [0; 6]);
+    }
+
+    #[test]
+    fn fold_constants_same_stack() {
+        // 5 * 13 is pushed back onto stack 3, then printed
+        let source = format!("흑... 형..... 형{} 하앗... 흑.", ".".repeat(13));
+        assert_folds_to(&source, 3);
+        let folded = fold_constants(Parser::new(&source).collect());
+        assert_eq!(folded[1].hangul_times_dots(), 65);
+    }
+
+    #[test]
+    fn fold_constants_other_stack() {
+        // 30 + 36 is printed straight away; 4 stays on stack 3 and is added in later
+        let source = format!(
+            "흑... 형.... 형{} 형{} 하앙. 형.... 하앙... 흑.",
+            ".".repeat(30),
+            ".".repeat(36),
+        );
+        assert_folds_to(&source, 5);
+    }
+
+    #[test]
+    fn fold_constants_unsafe() {
+        // Unknown selection at the start of the program
+        assert_folds_to("형.. 형... 하앙... 흑.", 4);
+        // Pushes print while stdout is selected
+        assert_folds_to("흑. 형.. 형... 하앙...", 4);
+        // A heart makes the push a possible jump target
+        assert_folds_to("흑... 형.. 형...♥ 하앙... 흑.", 5);
+        // Not enough constants to pop
+        assert_folds_to("흑... 형.. 하아앙... 흑.", 4);
+    }
+
+    #[test]
+    fn referenced_stacks_targets() {
+        let instructions = Parser::new("형..... 하앙.. 흑....... 흣. 형...").collect::<Vec<_>>();
+        let stacks = referenced_stacks(&instructions);
+        assert_eq!(stacks.into_iter().collect::<Vec<_>>(), vec![1, 2, 3, 7]);
+        assert_eq!(
+            referenced_stacks(&[]).into_iter().collect::<Vec<_>>(),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn to_dot_trees() {
+        let instructions = Parser::new("형 항.♥?♡!💖").collect::<Vec<_>>();
+        let expected = "\
+digraph hearts {
+  i0 [shape=box, label=\"[0] 형\"];
+  i1 [shape=box, label=\"[1] 항.♥?♡!💖\"];
+  i1 -> i1_0;
+  i1_0 [label=\"<\"];
+  i1_0 -> i1_1 [label=\"yes\"];
+  i1_1 [label=\"♥\"];
+  i1_0 -> i1_2 [label=\"no\"];
+  i1_2 [label=\"=\"];
+  i1_2 -> i1_3 [label=\"yes\"];
+  i1_3 [label=\"♡\"];
+  i1_2 -> i1_4 [label=\"no\"];
+  i1_4 [label=\"💖\"];
+}
+";
+        assert_eq!(to_dot(&instructions), expected);
+    }
+
+    #[test]
+    fn lint_zero_dot_push() {
+        let instructions = Parser::new("형 혀엉. 흑 혀어엉♥").collect::<Vec<_>>();
+        let lints = lint(&instructions);
+        let zero_dot_push = |position| Lint {
+            kind: LintKind::ZeroDotPush,
+            position,
+            span: instructions[position].span().cloned(),
+        };
+        assert_eq!(lints, vec![zero_dot_push(0), zero_dot_push(3)]);
+        assert_eq!(lints[1].span.as_ref().unwrap().bytes, 16..28);
+    }
+
+    #[test]
+    fn explain_instructions() {
+        let source = "형... 하앙.. 핫. 흣 흐읍... 흐으윽. 혀엉......♥?♡!💖";
+        let explanations = Parser::new(source)
+            .map(|instr| explain(&instr))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            explanations,
+            vec![
+                "push 3 onto the selected stack",
+                "pop the top 2 values and push their sum onto stack 2 (error output)",
+                "move the top value onto stack 1 (output)",
+                "negate the top value and push a copy onto stack 0 (input)",
+                "replace the top 2 values with their reciprocals and push the product of those \
+                 onto stack 3",
+                "copy the top value 3 times onto stack 1 (output), then select it",
+                "push 12 onto the selected stack; then pop a value; if it is less than 12, jump \
+                 to the label for ♥ at 12, otherwise pop a value; if it equals 12, return to the \
+                 last jump, otherwise jump to the label for 💖 at 12",
+            ]
+        );
+    }
+}
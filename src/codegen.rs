@@ -0,0 +1,67 @@
+//! Helpers for generating Hyeong programs.
+
+use super::structure::{HeartTree, Instruction, OperationType};
+
+/// Factors `value` into the `(hangul_count, dots)` of the push with the shortest source, as
+/// written by `Instruction::to_source`.
+///
+/// A hangul takes three bytes and a dot one, so this prefers more dots: primes are pushed with
+/// a single `형` and `value` dots. Zero is pushed as `형` without dots. This tries every divisor
+/// up to the square root of `value`, which is quick for any value whose push fits in memory.
+pub fn push_for(value: u64) -> (u64, u64) {
+    if value == 0 {
+        return (1, 0);
+    }
+    let cost = |(hangul, dots): (u64, u64)| hangul as u128 * 3 + dots as u128;
+    let mut best = (1, value);
+    let mut small = 1;
+    while small <= value / small {
+        if value.is_multiple_of(small) {
+            let large = value / small;
+            for candidate in [(small, large), (large, small)] {
+                if cost(candidate) < cost(best) {
+                    best = candidate;
+                }
+            }
+        }
+        small += 1;
+    }
+    best
+}
+
+/// An instruction pushing `value`, spelled as `push_for` factors it.
+pub fn push_instruction(value: u64) -> Instruction {
+    let (hangul, dots) = push_for(value);
+    Instruction::build(OperationType::Push, hangul, dots, HeartTree::Nil)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::Parser;
+    use super::{push_for, push_instruction, OperationType};
+
+    #[test]
+    fn push_for_values() {
+        assert_eq!(push_for(0), (1, 0));
+        assert_eq!(push_for(1), (1, 1));
+        assert_eq!(push_for(13), (1, 13));
+        // 3 * 12 is as short as 4 * 9, with fewer hangul
+        assert_eq!(push_for(36), (3, 12));
+        assert_eq!(push_for(65), (5, 13));
+        for value in 0..200 {
+            let (hangul, dots) = push_for(value);
+            assert_eq!(hangul * dots, value);
+        }
+    }
+
+    #[test]
+    fn push_instruction_round_trip() {
+        for value in [0, 1, 2, 44, 65, 97, 1000] {
+            let instr = push_instruction(value);
+            assert_eq!(instr.operation_type(), OperationType::Push);
+            assert_eq!(instr.hangul_times_dots(), value);
+            let source = instr.to_source().unwrap();
+            assert_eq!(Parser::new(&source).collect::<Vec<_>>(), vec![instr]);
+        }
+    }
+}
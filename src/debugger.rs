@@ -0,0 +1,433 @@
+//! An interactive debugger, driving a `Processor` one command at a time.
+
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use super::processor::{Processor, StepOutcome};
+use super::rational::HyeongRational;
+use super::structure::Instruction;
+
+/// Summary of the commands, as printed by `help`.
+pub const HELP: &str = "\
+step [N]      (s) execute N instructions, 1 by default
+continue      (c) run until a breakpoint or the end of the program
+break [N]     (b) set a breakpoint before instruction N, or list breakpoints
+delete N      (d) remove the breakpoint before instruction N
+list          (l) print the instructions around the next one
+stacks        (p) print the selected stack and every non-empty stack
+stack N           print the contents of stack N
+labels            print the labels set so far and the last jump
+help          (h) print this summary
+quit          (q) leave the debugger
+";
+
+/// A debugger command, parsed from a line like `break 3`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Step(u64),
+    Continue,
+    /// Sets a breakpoint, or lists them without a position.
+    Break(Option<usize>),
+    Delete(usize),
+    List,
+    Stacks,
+    Stack(u64),
+    Labels,
+    Help,
+    Quit,
+}
+
+/// Why a line isn't a debugger command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CommandError {
+    Empty,
+    Unknown(String),
+    /// The command is missing an argument, or has one that isn't a number.
+    BadArgument(&'static str),
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CommandError::Empty => write!(f, "no command given"),
+            CommandError::Unknown(name) => write!(f, "unknown command '{}'; try 'help'", name),
+            CommandError::BadArgument(usage) => write!(f, "usage: {}", usage),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(line: &str) -> Result<Self, CommandError> {
+        let mut words = line.split_whitespace();
+        let name = words.next().ok_or(CommandError::Empty)?;
+        let arg = words.next();
+        if words.next().is_some() {
+            return Err(CommandError::BadArgument(usage(name)));
+        }
+        let number = |usage| {
+            arg.map(str::parse)
+                .transpose()
+                .map_err(|_| CommandError::BadArgument(usage))
+        };
+        let required = |usage| number(usage)?.ok_or(CommandError::BadArgument(usage));
+        let command = match name {
+            "step" | "s" => Command::Step(number(usage(name))?.unwrap_or(1)),
+            "break" | "b" => Command::Break(number(usage(name))?.map(|n: u64| n as usize)),
+            "delete" | "d" => Command::Delete(required(usage(name))? as usize),
+            "stack" => Command::Stack(required(usage(name))?),
+            _ => {
+                if arg.is_some() {
+                    return Err(CommandError::BadArgument(usage(name)));
+                }
+                match name {
+                    "continue" | "c" => Command::Continue,
+                    "list" | "l" => Command::List,
+                    "stacks" | "p" => Command::Stacks,
+                    "labels" => Command::Labels,
+                    "help" | "h" => Command::Help,
+                    "quit" | "q" => Command::Quit,
+                    _ => return Err(CommandError::Unknown(name.to_owned())),
+                }
+            }
+        };
+        Ok(command)
+    }
+}
+
+fn usage(name: &str) -> &'static str {
+    match name {
+        "step" | "s" => "step [N]",
+        "break" | "b" => "break [N]",
+        "delete" | "d" => "delete N",
+        "stack" => "stack N",
+        "continue" | "c" => "continue",
+        "list" | "l" => "list",
+        "stacks" | "p" => "stacks",
+        "labels" => "labels",
+        "help" | "h" => "help",
+        "quit" | "q" => "quit",
+        _ => "help",
+    }
+}
+
+/// Exact value of a stack entry, as a number rather than the character it would print as.
+fn describe_value(value: &HyeongRational) -> String {
+    match value.as_rational() {
+        Some(r) => r.to_string(),
+        None => "NaN".to_owned(),
+    }
+}
+
+fn describe_values(values: &[HyeongRational]) -> String {
+    if values.is_empty() {
+        return "(empty)".to_owned();
+    }
+    let values = values.iter().map(describe_value).collect::<Vec<_>>();
+    values.join(" ")
+}
+
+fn describe_instruction(instr: &Instruction) -> String {
+    instr.to_source().unwrap_or_else(|| format!("{:?}", instr))
+}
+
+/// Runs a program under the control of debugger commands.
+///
+/// Positions are those of `Processor::position`, the same as `--listing` prints. Once the
+/// program has ended the stacks can still be inspected, but no more instructions run.
+pub struct Debugger<P, I: Read, O: Write, E: Write> {
+    processor: Processor<P, I, O, E>,
+    breakpoints: BTreeSet<usize>,
+    exit_code: Option<isize>,
+}
+
+impl<P, I, O, E> Debugger<P, I, O, E>
+where
+    P: Iterator<Item = Instruction>,
+    I: Read,
+    O: Write,
+    E: Write,
+{
+    pub fn new(processor: Processor<P, I, O, E>) -> Self {
+        Debugger {
+            processor,
+            breakpoints: BTreeSet::new(),
+            exit_code: None,
+        }
+    }
+
+    pub fn processor(&self) -> &Processor<P, I, O, E> {
+        &self.processor
+    }
+
+    pub fn processor_mut(&mut self) -> &mut Processor<P, I, O, E> {
+        &mut self.processor
+    }
+
+    /// The exit code, once the program has ended.
+    pub fn exit_code(&self) -> Option<isize> {
+        self.exit_code
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    /// Executes `command`, writing what it reports to `out`. `Quit` does nothing; leaving is up
+    /// to the caller.
+    pub fn execute<W: Write>(&mut self, command: Command, out: &mut W) -> io::Result<()> {
+        match command {
+            Command::Step(count) => {
+                for _ in 0..count {
+                    if !self.step(out)? {
+                        return Ok(());
+                    }
+                }
+                self.write_next(out)
+            }
+            Command::Continue => {
+                if !self.step(out)? {
+                    return Ok(());
+                }
+                loop {
+                    let position = self
+                        .processor
+                        .next_instruction()
+                        .map(|(position, _)| position);
+                    if let Some(position) = position.filter(|p| self.breakpoints.contains(p)) {
+                        writeln!(out, "Breakpoint at [{}]", position)?;
+                        return self.write_next(out);
+                    }
+                    if !self.step(out)? {
+                        return Ok(());
+                    }
+                }
+            }
+            Command::Break(Some(position)) => {
+                self.breakpoints.insert(position);
+                writeln!(out, "Breakpoint set at [{}]", position)
+            }
+            Command::Break(None) => {
+                if self.breakpoints.is_empty() {
+                    return writeln!(out, "No breakpoints");
+                }
+                for position in &self.breakpoints {
+                    writeln!(out, "Breakpoint at [{}]", position)?;
+                }
+                Ok(())
+            }
+            Command::Delete(position) => {
+                if self.breakpoints.remove(&position) {
+                    writeln!(out, "Breakpoint at [{}] removed", position)
+                } else {
+                    writeln!(out, "No breakpoint at [{}]", position)
+                }
+            }
+            Command::List => self.write_listing(out),
+            Command::Stacks => {
+                let snapshot = self.processor.stack_manager().snapshot();
+                writeln!(out, "selected: {}", snapshot.selected)?;
+                if !snapshot.input.is_empty() {
+                    writeln!(out, "input: {}", describe_values(&snapshot.input))?;
+                }
+                for (id, values) in &snapshot.stacks {
+                    writeln!(out, "{}: {}", id, describe_values(values))?;
+                }
+                Ok(())
+            }
+            Command::Stack(id) => {
+                let stacks = self.processor.stack_manager();
+                let layout = stacks.layout();
+                if id == layout.stdout || id == layout.stderr {
+                    return writeln!(out, "{}: output, always empty", id);
+                }
+                let snapshot = stacks.snapshot();
+                let values = if id == layout.stdin {
+                    &snapshot.input
+                } else {
+                    snapshot.stacks.get(&id).map_or(&[][..], |v| &v[..])
+                };
+                writeln!(out, "{}: {}", id, describe_values(values))
+            }
+            Command::Labels => {
+                let labels = self.processor.jump_strategy();
+                let mut all = labels.iter().collect::<Vec<_>>();
+                all.sort_unstable();
+                if all.is_empty() {
+                    writeln!(out, "No labels")?;
+                }
+                for (param, id, position) in all {
+                    writeln!(out, "param {}, heart {}: [{}]", param, id, position)?;
+                }
+                if let Some(position) = labels.last_jump() {
+                    writeln!(out, "last jump from [{}]", position)?;
+                }
+                Ok(())
+            }
+            Command::Help => out.write_all(HELP.as_bytes()),
+            Command::Quit => Ok(()),
+        }
+    }
+
+    /// Executes one instruction, reporting the end of the program. Returns whether it is still
+    /// running.
+    fn step<W: Write>(&mut self, out: &mut W) -> io::Result<bool> {
+        if self.exit_code.is_none() {
+            if let StepOutcome::Exit(code) = self.processor.step() {
+                self.exit_code = Some(code);
+            }
+        }
+        match self.exit_code {
+            Some(code) => {
+                writeln!(out, "The program has ended with exit code {}", code)?;
+                Ok(false)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn write_next<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        match self.processor.next_instruction() {
+            Some((position, instr)) => {
+                writeln!(out, "[{}] {}", position, describe_instruction(instr))
+            }
+            None => writeln!(out, "The program is empty"),
+        }
+    }
+
+    /// Lists the loaded instructions within a few positions of the next one, marking it with
+    /// `=>` and breakpoints with `*`.
+    fn write_listing<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        let next = match self.processor.next_instruction() {
+            Some((position, _)) => position,
+            None => return writeln!(out, "The program is empty"),
+        };
+        let instructions = self.processor.instructions();
+        let start = next.saturating_sub(3);
+        let end = instructions.len().min(next + 4);
+        for (position, instr) in instructions[start..end].iter().enumerate() {
+            let position = start + position;
+            let marker = if position == next { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&position) {
+                '*'
+            } else {
+                ' '
+            };
+            writeln!(
+                out,
+                "{}{}[{}] {}",
+                marker,
+                breakpoint,
+                position,
+                describe_instruction(instr)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::Parser;
+    use super::super::processor::Processor;
+    use super::super::stack::{HyeongReadStack, HyeongWriteStack, StackManager};
+    use super::{Command, CommandError, Debugger};
+
+    fn run(debugger_commands: &[&str], source: &str, input: &str) -> String {
+        let stdin = HyeongReadStack::new(input.as_bytes());
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let processor = Processor::with_stack_manager(Parser::new(source), stacks);
+        let mut debugger = Debugger::new(processor);
+        let mut out = vec![];
+        for command in debugger_commands {
+            let command = command.parse().unwrap();
+            debugger.execute(command, &mut out).unwrap();
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!("s".parse(), Ok(Command::Step(1)));
+        assert_eq!("step 10".parse(), Ok(Command::Step(10)));
+        assert_eq!(" b  3 ".parse(), Ok(Command::Break(Some(3))));
+        assert_eq!("break".parse(), Ok(Command::Break(None)));
+        assert_eq!("stack 4".parse(), Ok(Command::Stack(4)));
+        assert_eq!("c".parse(), Ok(Command::Continue));
+        assert_eq!("".parse::<Command>(), Err(CommandError::Empty));
+        assert_eq!(
+            "jump".parse::<Command>(),
+            Err(CommandError::Unknown("jump".to_owned()))
+        );
+        assert_eq!(
+            "delete".parse::<Command>(),
+            Err(CommandError::BadArgument("delete N"))
+        );
+        assert_eq!(
+            "step x".parse::<Command>(),
+            Err(CommandError::BadArgument("step [N]"))
+        );
+        assert_eq!(
+            "quit now".parse::<Command>(),
+            Err(CommandError::BadArgument("quit"))
+        );
+    }
+
+    #[test]
+    fn step_and_inspect() {
+        let out = run(
+            &["step", "stacks", "step 2", "stack 3"],
+            "형. 형.. 항... 흑",
+            "",
+        );
+        assert_eq!(out, "[1] 형..\nselected: 3\n3: 1\n[3] 흑\n3: 1 2\n");
+    }
+
+    #[test]
+    fn breakpoints() {
+        // Loops forever, selecting stack 4 at the third instruction
+        let source = "형.♥ 형. 흑.... 형.♥";
+        let out = run(
+            &["b 2", "c", "p", "c", "break", "d 2", "l", "labels"],
+            source,
+            "",
+        );
+        assert_eq!(
+            out,
+            "Breakpoint set at [2]\n\
+             Breakpoint at [2]\n\
+             [2] 흑....\n\
+             selected: 3\n\
+             3: 1 1\n\
+             Breakpoint at [2]\n\
+             [2] 흑....\n\
+             Breakpoint at [2]\n\
+             Breakpoint at [2] removed\n   \
+             [0] 형.♥\n   \
+             [1] 형.\n\
+             => [2] 흑....\n   \
+             [3] 형.♥\n\
+             param 1, heart 0: [0]\n\
+             last jump from [3]\n"
+        );
+    }
+
+    #[test]
+    fn program_end() {
+        let out = run(&["c", "s", "labels", "stack 1"], "흑 항.. 항. 흑..", "A");
+        assert_eq!(
+            out,
+            "The program has ended with exit code 1\n\
+             The program has ended with exit code 1\n\
+             No labels\n\
+             1: output, always empty\n"
+        );
+    }
+}
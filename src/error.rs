@@ -0,0 +1,60 @@
+use std::fmt::{self, Display, Formatter};
+use std::io;
+
+use super::parser::ParseError;
+
+/// Everything which can go wrong parsing and running a program, short of the program itself
+/// ending abnormally, which is told by its exit code.
+#[derive(Debug)]
+pub enum Error {
+    /// The source has a malformed construct.
+    Parse(ParseError),
+    /// Writing the output failed, e.g. because the other end of a pipe was closed.
+    Io(io::Error),
+    /// The characters don't make an operation, like `혀` and `읏`, or `하` without an end.
+    InvalidOperation { start: char, end: Option<char> },
+    /// The instruction at `position` computed NaN while that is fatal; see
+    /// `StackManager::set_fatal_nan`.
+    UnexpectedNan { position: usize },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(e) => write!(f, "parse error: {}", e),
+            Error::Io(e) => write!(f, "IO error: {}", e),
+            Error::InvalidOperation { start, end: None } => {
+                write!(f, "invalid operation: '{}' without an end", start)
+            }
+            Error::InvalidOperation {
+                start,
+                end: Some(end),
+            } => write!(f, "invalid operation: '{}' ending with '{}'", start, end),
+            Error::UnexpectedNan { position } => {
+                write!(f, "unexpected NaN at instruction {}", position)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Parse(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::InvalidOperation { .. } | Error::UnexpectedNan { .. } => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,77 @@
+use super::error::Error;
+use super::processor::Processor;
+use super::program::Program;
+use super::stack::{HyeongReadStack, HyeongWriteStack, StackManager, StringWriter};
+
+/// Everything a program evaluated by `eval` produced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvalResult {
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: isize,
+}
+
+/// Why a program couldn't be evaluated. This is the crate-wide `Error`, under its older name.
+pub type RunError = Error;
+
+/// Runs `source` to the end with `input` as stdin, and collects its output.
+///
+/// Unlike the interpreter, this rejects sources with malformed constructs instead of skipping
+/// them. Like any Hyeong program, this doesn't return if the program never exits.
+pub fn eval(source: &str, input: &str) -> Result<EvalResult, Error> {
+    let program = Program::parse(source)?;
+    let mut stdout = StringWriter::new();
+    let mut stderr = StringWriter::new();
+    let (exit_code, result) = {
+        let stdin = HyeongReadStack::new(input.as_bytes());
+        let stdout = HyeongWriteStack::new(&mut stdout);
+        let stderr = HyeongWriteStack::new(&mut stderr);
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        Processor::from_program(&program, stacks).run()
+    };
+    result?;
+    Ok(EvalResult {
+        stdout: stdout.into_string(),
+        stderr: stderr.into_string(),
+        exit_code,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::ParseErrorKind;
+    use super::{eval, RunError};
+
+    #[test]
+    fn hello_world() {
+        let result = eval(include_str!("../snippets/hello-world.hyeong"), "").unwrap();
+        assert_eq!(
+            result.stdout.as_bytes(),
+            include_bytes!("../snippets/hello-world.stdout")
+        );
+        assert!(result.stderr.is_empty());
+        assert_eq!(
+            result.exit_code,
+            include!("../snippets/hello-world.exitcode")
+        );
+    }
+
+    #[test]
+    fn input_and_nan() {
+        // Prints the NaN pushed onto stdin to stderr, echoes a character of input, then ends by
+        // printing what's read past the end of input to stderr
+        let result = eval("흑 항.. 항. 흑..", "A").unwrap();
+        assert_eq!(result.stdout, "A");
+        assert_eq!(result.stderr, "너무 커엇...".repeat(2));
+    }
+
+    #[test]
+    fn parse_error() {
+        match eval("형 흐", "") {
+            Err(RunError::Parse(e)) => {
+                assert_eq!(e.kind, ParseErrorKind::UnterminatedHangul('흐'))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
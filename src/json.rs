@@ -0,0 +1,207 @@
+//! A JSON form of programs, for tools not written in Rust.
+//!
+//! A program is an array of instructions, each an object with these fields:
+//!
+//! - `op`: one of `"push"`, `"add"`, `"multiply"`, `"negate"`, `"reciprocate"` and
+//!   `"duplicate"`.
+//! - `hangul_count` and `dots`: non-negative integers. `hangul_count` must be positive, and
+//!   their product must fit in 64 bits.
+//! - `hearts`: the heart tree, or `null` if there is none. May be left out when reading.
+//!
+//! A heart tree is an object whose `type` is one of:
+//!
+//! - `"heart"`, with the index of the heart mark as `id`, as in `♥` being 0.
+//! - `"return"`, for `♡`.
+//! - `"less_than"` and `"equals"`, with the trees taken when the comparison holds as `yes` and
+//!   when it doesn't as `no`. Either may be `null`.
+//!
+//! For example, `하앙..♥?♡` is
+//!
+//! ```json
+//! [
+//!   {
+//!     "op": "add",
+//!     "hangul_count": 2,
+//!     "dots": 2,
+//!     "hearts": {
+//!       "type": "less_than",
+//!       "yes": { "type": "heart", "id": 0 },
+//!       "no": { "type": "return" }
+//!     }
+//!   }
+//! ]
+//! ```
+//!
+//! Unknown fields are ignored when reading.
+
+use serde::{Deserialize, Serialize};
+
+use super::structure::{HeartTree, Instruction, OperationType};
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JsonOp {
+    Push,
+    Add,
+    Multiply,
+    Negate,
+    Reciprocate,
+    Duplicate,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonHearts {
+    Heart {
+        id: u64,
+    },
+    Return,
+    LessThan {
+        yes: Option<Box<JsonHearts>>,
+        no: Option<Box<JsonHearts>>,
+    },
+    Equals {
+        yes: Option<Box<JsonHearts>>,
+        no: Option<Box<JsonHearts>>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct JsonInstruction {
+    op: JsonOp,
+    hangul_count: u64,
+    dots: u64,
+    #[serde(default)]
+    hearts: Option<JsonHearts>,
+}
+
+impl From<OperationType> for JsonOp {
+    fn from(op: OperationType) -> Self {
+        match op {
+            OperationType::Push => JsonOp::Push,
+            OperationType::Add => JsonOp::Add,
+            OperationType::Multiply => JsonOp::Multiply,
+            OperationType::Negate => JsonOp::Negate,
+            OperationType::Reciprocate => JsonOp::Reciprocate,
+            OperationType::Duplicate => JsonOp::Duplicate,
+        }
+    }
+}
+
+impl From<JsonOp> for OperationType {
+    fn from(op: JsonOp) -> Self {
+        match op {
+            JsonOp::Push => OperationType::Push,
+            JsonOp::Add => OperationType::Add,
+            JsonOp::Multiply => OperationType::Multiply,
+            JsonOp::Negate => OperationType::Negate,
+            JsonOp::Reciprocate => OperationType::Reciprocate,
+            JsonOp::Duplicate => OperationType::Duplicate,
+        }
+    }
+}
+
+fn hearts_to_json(tree: &HeartTree) -> Option<Box<JsonHearts>> {
+    let hearts = match tree {
+        HeartTree::Nil => return None,
+        HeartTree::Heart(id) => JsonHearts::Heart { id: *id },
+        HeartTree::Return => JsonHearts::Return,
+        HeartTree::LessThan(l, r) => JsonHearts::LessThan {
+            yes: hearts_to_json(l),
+            no: hearts_to_json(r),
+        },
+        HeartTree::Equals(l, r) => JsonHearts::Equals {
+            yes: hearts_to_json(l),
+            no: hearts_to_json(r),
+        },
+    };
+    Some(Box::new(hearts))
+}
+
+fn hearts_from_json(hearts: Option<JsonHearts>) -> HeartTree {
+    let branch = |hearts: Option<Box<JsonHearts>>| Box::new(hearts_from_json(hearts.map(|h| *h)));
+    match hearts {
+        None => HeartTree::Nil,
+        Some(JsonHearts::Heart { id }) => HeartTree::Heart(id),
+        Some(JsonHearts::Return) => HeartTree::Return,
+        Some(JsonHearts::LessThan { yes, no }) => HeartTree::LessThan(branch(yes), branch(no)),
+        Some(JsonHearts::Equals { yes, no }) => HeartTree::Equals(branch(yes), branch(no)),
+    }
+}
+
+/// Writes `instructions` as a JSON program, pretty-printed.
+pub fn to_json(instructions: &[Instruction]) -> String {
+    let program = instructions
+        .iter()
+        .map(|instr| JsonInstruction {
+            op: instr.operation_type().into(),
+            hangul_count: instr.hangul_count(),
+            dots: instr.dots(),
+            hearts: hearts_to_json(instr.heart_tree()).map(|h| *h),
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string_pretty(&program).expect("programs can always be written as JSON")
+}
+
+/// Reads a JSON program. Instructions which aren't well-formed are rejected, as
+/// `Instruction::is_well_formed` tells.
+pub fn from_json(json: &str) -> Result<Vec<Instruction>, serde_json::Error> {
+    let program: Vec<JsonInstruction> = serde_json::from_str(json)?;
+    program
+        .into_iter()
+        .map(|instr| {
+            let (hangul_count, dots) = (instr.hangul_count, instr.dots);
+            let hearts = hearts_from_json(instr.hearts);
+            let instr = Instruction::build(instr.op.into(), hangul_count, dots, hearts);
+            if !instr.is_well_formed() {
+                return Err(serde::de::Error::custom(format!(
+                    "invalid instruction with {} hangul and {} dots",
+                    hangul_count, dots
+                )));
+            }
+            Ok(instr)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::Parser;
+    use super::{from_json, to_json};
+
+    #[test]
+    fn round_trip() {
+        let sources = [
+            include_str!("../snippets/hello-world.hyeong"),
+            include_str!("../snippets/fibonacci.hyeong"),
+            "혀어어엉… 하아앗.⋮ 흐으으윽...♥!♡?💖!!?♥? 흡.♡♥!💝 하아앙? 흣!♥ 핫",
+        ];
+        for source in &sources {
+            let instructions = Parser::new(source).collect::<Vec<_>>();
+            assert_eq!(from_json(&to_json(&instructions)).unwrap(), instructions);
+        }
+    }
+
+    #[test]
+    fn schema() {
+        let json = r#"[
+            {
+                "op": "add",
+                "hangul_count": 2,
+                "dots": 2,
+                "hearts": {
+                    "type": "less_than",
+                    "yes": { "type": "heart", "id": 0 },
+                    "no": { "type": "return" }
+                }
+            },
+            { "op": "duplicate", "hangul_count": 1, "dots": 3, "comment": "ignored" }
+        ]"#;
+        let instructions = Parser::new("하앙..♥?♡ 흑...").collect::<Vec<_>>();
+        assert_eq!(from_json(json).unwrap(), instructions);
+        assert!(from_json(r#"[{ "op": "jump", "hangul_count": 1, "dots": 0 }]"#).is_err());
+        let huge = r#"[{"op":"push","hangul_count":4294967296,"dots":4294967296}]"#;
+        assert!(from_json(huge).is_err());
+        assert!(from_json(r#"[{ "op": "push", "hangul_count": 0, "dots": 1 }]"#).is_err());
+    }
+}
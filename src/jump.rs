@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+
+/// Decides where execution continues after an instruction's heart tree is resolved.
+pub trait JumpStrategy {
+    /// The instruction at `position`, whose `hangul_times_dots` is `param`, resolved to the
+    /// heart `id`. Returns the position to jump to, or `None` to fall through.
+    fn heart(&mut self, param: u64, id: u64, position: usize) -> Option<usize>;
+
+    /// The instruction at `position` resolved to the return heart. Returns the position to
+    /// jump to, or `None` to fall through.
+    fn ret(&mut self, position: usize) -> Option<usize>;
+}
+
+/// The jump semantics of the language.
+///
+/// The first instruction to resolve to a `(param, id)` pair becomes the label for it, and any
+/// later instruction resolving to the same pair jumps there. A return heart jumps back to the
+/// instruction which made the last jump.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(from = "LabelsRepr", into = "LabelsRepr")
+)]
+pub struct Labels {
+    labels: HashMap<(u64, u64), usize>,
+    last_jump: Option<usize>,
+}
+
+// Tuple keys can't be map keys in formats like JSON, so labels are serialized as a list
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct LabelsRepr {
+    labels: Vec<(u64, u64, usize)>,
+    last_jump: Option<usize>,
+}
+
+#[cfg(feature = "serde")]
+impl From<LabelsRepr> for Labels {
+    fn from(repr: LabelsRepr) -> Self {
+        Labels {
+            labels: repr
+                .labels
+                .into_iter()
+                .map(|(param, id, position)| ((param, id), position))
+                .collect(),
+            last_jump: repr.last_jump,
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<Labels> for LabelsRepr {
+    fn from(labels: Labels) -> Self {
+        let mut pairs = labels
+            .labels
+            .into_iter()
+            .map(|((param, id), position)| (param, id, position))
+            .collect::<Vec<_>>();
+        pairs.sort_unstable();
+        LabelsRepr {
+            labels: pairs,
+            last_jump: labels.last_jump,
+        }
+    }
+}
+
+impl Labels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn label(&self, param: u64, id: u64) -> Option<usize> {
+        self.labels.get(&(param, id)).copied()
+    }
+
+    pub fn last_jump(&self) -> Option<usize> {
+        self.last_jump
+    }
+
+    /// Every label set so far as `(param, id, position)`, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64, usize)> + '_ {
+        self.labels
+            .iter()
+            .map(|(&(param, id), &position)| (param, id, position))
+    }
+}
+
+impl JumpStrategy for Labels {
+    fn heart(&mut self, param: u64, id: u64, position: usize) -> Option<usize> {
+        let next = *self.labels.entry((param, id)).or_insert(position);
+        if next != position {
+            self.last_jump = Some(position);
+            Some(next)
+        } else {
+            None
+        }
+    }
+
+    fn ret(&mut self, _position: usize) -> Option<usize> {
+        self.last_jump
+    }
+}
+
+impl<T: JumpStrategy + ?Sized> JumpStrategy for &mut T {
+    fn heart(&mut self, param: u64, id: u64, position: usize) -> Option<usize> {
+        (**self).heart(param, id, position)
+    }
+
+    fn ret(&mut self, position: usize) -> Option<usize> {
+        (**self).ret(position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JumpStrategy, Labels};
+
+    #[test]
+    fn labels() {
+        let mut labels = Labels::new();
+        assert_eq!(labels.ret(0), None);
+        assert_eq!(labels.heart(6, 0, 2), None);
+        assert_eq!(labels.heart(6, 1, 3), None);
+        assert_eq!(labels.heart(6, 0, 2), None);
+        assert_eq!(labels.label(6, 0), Some(2));
+        assert_eq!(labels.heart(6, 0, 5), Some(2));
+        assert_eq!(labels.last_jump(), Some(5));
+        assert_eq!(labels.ret(7), Some(5));
+        let mut all = labels.iter().collect::<Vec<_>>();
+        all.sort_unstable();
+        assert_eq!(all, vec![(6, 0, 2), (6, 1, 3)]);
+    }
+}
@@ -1,10 +1,55 @@
+//! An interpreter for the Hyeong esoteric programming language.
+//!
+//! The quickest way to run a program is `eval`:
+//!
+//! ```
+//! // Echoes a character of input to stdout
+//! let result = rshyeong::eval("흑 항.. 항. 흑..", "A").unwrap();
+//! assert_eq!(result.stdout, "A");
+//! ```
+//!
+//! For more control, parse the source with `Parser` or `Program` and run it with a
+//! `Processor` over a `StackManager`.
+
+pub mod analysis;
+pub mod codegen;
+pub mod debugger;
+mod error;
+mod eval;
+#[cfg(feature = "json")]
+pub mod json;
+mod jump;
 mod parser;
 mod processor;
+mod program;
 mod rational;
 mod stack;
 mod structure;
-mod utf8;
+#[cfg(feature = "test-util")]
+pub mod testutil;
+pub mod utf8;
 
-pub use self::parser::Parser;
-pub use self::processor::Processor;
-pub use self::stack::{HyeongReadStack, HyeongWriteStack, StackManager};
+pub use self::error::Error;
+pub use self::eval::{eval, EvalResult, RunError};
+pub use self::jump::{JumpStrategy, Labels};
+pub use self::parser::{
+    is_hangul_syllable, recognized_tokens, validate, Assembler, Coverage, Event, HangulStartType,
+    ParseError, ParseErrorKind, ParseWarning, ParseWarningKind, Parser, ProgramInfo, Token,
+    FORMAT_CHARS,
+};
+pub use self::processor::{
+    CoreDump, Processor, ProcessorSnapshot, RunResult, StepOutcome, TimingProfile, TraceEvent,
+    Transcript, FUEL_EXIT_CODE, JUMP_LIMIT_EXIT_CODE, TIMEOUT_EXIT_CODE,
+};
+pub use self::program::Program;
+pub use self::rational::{DivisionByZero, HyeongRational, ReciprocalPolicy, UNICODE_SCALAR_BOUND};
+pub use self::stack::{
+    CallbackWriter, CountingReader, HeartResult, HyeongReadStack, HyeongStack, HyeongWriteStack,
+    InputMode, NegativeMode, OutputMode, SharedWriter, StackLayout, StackManager, StackSnapshot,
+    StringWriter, WriteStats, DEPTH_LIMIT_EXIT_CODE, EOF_LIMIT_EXIT_CODE, NAN_EXIT_CODE,
+    OUTPUT_LIMIT_EXIT_CODE, STDERR_EXIT_CODE, WRITE_ERROR_EXIT_CODE,
+};
+pub use self::structure::{
+    decode_bytecode, encode_bytecode, BytecodeError, HeartTree, Instruction, LineColumn, Operation,
+    OperationType, Span, MAX_HEART_DEPTH,
+};
@@ -1,45 +1,471 @@
+use std::fmt::Display;
 use std::fs::File;
-use std::io::{BufWriter, Read, Write};
+use std::io::{BufRead, BufWriter, Read, Write};
+use std::num::NonZeroUsize;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
-use rshyeong::{HyeongReadStack, HyeongWriteStack, Processor, StackManager};
+use rshyeong::debugger::{Command as DebugCommand, CommandError as DebugCommandError, Debugger};
+use rshyeong::{
+    HyeongReadStack, HyeongWriteStack, InputMode, OutputMode, Processor, SharedWriter,
+    StackManager, OUTPUT_LIMIT_EXIT_CODE, TIMEOUT_EXIT_CODE,
+};
 
 #[derive(Debug, Parser)]
-#[clap(version, about)]
+#[clap(
+    version,
+    about,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Options {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(short, long, default_value = "-")]
     /// Input file, stdin by default
     input: PathBuf,
     /// Output file, stdout by default
     #[clap(short, long, default_value = "-")]
     output: PathBuf,
-    /// Input source code
-    source: PathBuf,
+    /// Print diagnostics for malformed constructs before running
+    #[clap(short = 'W', long)]
+    warnings: bool,
+    /// Only check that the sources parse without errors, without running them
+    #[clap(long)]
+    check: bool,
+    /// Print the sources in canonical form, one instruction per line, without running them
+    #[clap(long)]
+    format: bool,
+    /// Print the heart trees of the sources as a Graphviz graph, without running them
+    #[clap(long)]
+    dot: bool,
+    /// Print the sources in canonical form, each instruction followed by what it does, without
+    /// running them
+    #[clap(long)]
+    explain: bool,
+    /// Print the sources as a JSON program, without running them; needs the `json` feature
+    #[clap(long)]
+    emit_json: bool,
+    /// Read the sources as JSON programs like those `--emit-json` prints; needs the `json`
+    /// feature
+    #[clap(
+        long,
+        conflicts_with_all = &["warnings", "check", "format", "dot", "explain", "emit-json"]
+    )]
+    json: bool,
+    /// Read input one byte at a time instead of one UTF-8 character at a time
+    #[clap(long)]
+    byte_input: bool,
+    /// Print non-integer output values as exact fractions instead of characters
+    #[clap(long)]
+    rational_output: bool,
+    /// Write error output to the output file as well, like `2>&1`
+    #[clap(long)]
+    merge_stderr: bool,
+    /// Stop the program as soon as it writes to error output
+    #[clap(long)]
+    fatal_stderr: bool,
+    /// Stop the program as soon as arithmetic results in NaN, and report where
+    #[clap(long)]
+    fatal_nan: bool,
+    /// Stop the program once it has written this many bytes to the output
+    #[clap(long, value_name = "BYTES")]
+    max_output: Option<usize>,
+    /// Stop the program after this many consecutive reads past the end of input
+    #[clap(long, value_name = "READS")]
+    max_eof_reads: Option<NonZeroUsize>,
+    /// Stop the program once a stack would hold more than this many values
+    #[clap(long, value_name = "VALUES")]
+    max_stack_depth: Option<usize>,
+    /// Stop the program once it has jumped this many times
+    #[clap(long, value_name = "JUMPS")]
+    max_jumps: Option<u64>,
+    /// Stop the program once it has executed this many instructions
+    #[clap(long, value_name = "COUNT")]
+    max_instructions: Option<u64>,
+    /// Stop the program once it has run for this many seconds, keeping its output
+    #[clap(long, value_name = "SECONDS")]
+    timeout: Option<f64>,
+    /// Print each instruction to stderr as it is executed
+    #[clap(long)]
+    listing: bool,
+    /// Encoding of the sources, like `utf-16le` or `euc-kr`; needs the `encoding` feature
+    #[clap(long, value_name = "LABEL")]
+    encoding: Option<String>,
+    /// Input source code; multiple files are run as a single program, in order
+    #[clap(required = true)]
+    source: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Run the sources step by step, reading debugger commands like `step` and `break 3` from
+    /// stdin; `help` lists them
+    Debug(DebugOptions),
+}
+
+#[derive(Debug, clap::Args)]
+struct DebugOptions {
+    /// Input file; the program reads no input by default, as stdin holds the commands
+    #[clap(short, long)]
+    input: Option<PathBuf>,
+    /// Read input one byte at a time instead of one UTF-8 character at a time
+    #[clap(long)]
+    byte_input: bool,
+    /// Encoding of the sources, like `utf-16le` or `euc-kr`; needs the `encoding` feature
+    #[clap(long, value_name = "LABEL")]
+    encoding: Option<String>,
+    /// Input source code; multiple files are run as a single program, in order
+    #[clap(required = true)]
+    source: Vec<PathBuf>,
+}
+
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x1100..=0x115f
+        | 0x2e80..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff => 2,
+        _ => 1,
+    }
+}
+
+fn print_diagnostic<D: Display>(
+    path: &str,
+    source: &str,
+    span: Range<usize>,
+    message: D,
+    severity: &str,
+) {
+    let line_start = source[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = source[line_start..]
+        .find('\n')
+        .map(|i| line_start + i)
+        .unwrap_or(source.len());
+    let line_no = source[..line_start].matches('\n').count() + 1;
+    let prefix = &source[line_start..span.start];
+    let column = prefix.chars().count() + 1;
+    let padding: String = prefix
+        .chars()
+        .map(|c| {
+            if c == '\t' {
+                "\t".to_owned()
+            } else {
+                " ".repeat(char_width(c))
+            }
+        })
+        .collect();
+    let carets = "^".repeat(source[span].chars().map(char_width).sum());
+
+    eprintln!("{}:{}:{}: {}: {}", path, line_no, column, severity, message);
+    eprintln!(" {}", source[line_start..line_end].trim_end_matches('\r'));
+    eprintln!(" {}{}", padding, carets);
+}
+
+/// Transcodes a source file to UTF-8. Without a label it must be UTF-8 already.
+#[cfg(feature = "encoding")]
+fn decode_source(bytes: Vec<u8>, label: Option<&str>) -> Result<String, String> {
+    let label = match label {
+        Some(label) => label,
+        None => return String::from_utf8(bytes).map_err(|e| e.to_string()),
+    };
+    let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| format!("unknown encoding '{}'", label))?;
+    // A byte order mark overrides the label, as it does for browsers
+    let (source, actual, had_errors) = encoding.decode(&bytes);
+    if had_errors {
+        return Err(format!("source is not valid {}", actual.name()));
+    }
+    Ok(source.into_owned())
+}
+
+#[cfg(not(feature = "encoding"))]
+fn decode_source(bytes: Vec<u8>, label: Option<&str>) -> Result<String, String> {
+    match label {
+        None => String::from_utf8(bytes).map_err(|e| e.to_string()),
+        Some(label)
+            if label.eq_ignore_ascii_case("utf-8") || label.eq_ignore_ascii_case("utf8") =>
+        {
+            String::from_utf8(bytes).map_err(|e| e.to_string())
+        }
+        Some(label) => Err(format!(
+            "encoding '{}' needs the interpreter built with the `encoding` feature",
+            label
+        )),
+    }
+}
+
+#[cfg(feature = "json")]
+fn write_json(instructions: &[rshyeong::Instruction]) -> Result<String, String> {
+    Ok(rshyeong::json::to_json(instructions))
+}
+
+#[cfg(not(feature = "json"))]
+fn write_json(_instructions: &[rshyeong::Instruction]) -> Result<String, String> {
+    Err("JSON programs need the interpreter built with the `json` feature".to_owned())
+}
+
+#[cfg(feature = "json")]
+fn read_json(source: &str) -> Result<Vec<rshyeong::Instruction>, String> {
+    rshyeong::json::from_json(source).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "json"))]
+fn read_json(_source: &str) -> Result<Vec<rshyeong::Instruction>, String> {
+    Err("JSON programs need the interpreter built with the `json` feature".to_owned())
+}
+
+/// Reads and decodes the source files, exiting on failure. Each source comes with its path.
+fn read_sources(paths: Vec<PathBuf>, encoding: Option<&str>) -> Vec<(String, String)> {
+    let mut sources = vec![];
+    for source_path in paths {
+        let mut source = match File::open(&source_path) {
+            Ok(f) => f,
+            Err(e) => {
+                eprintln!("Cannot open source file: {}", e);
+                std::process::exit(2);
+            }
+        };
+        let mut bytes = vec![];
+        if let Err(e) = source.read_to_end(&mut bytes) {
+            eprintln!("Cannot read source file: {}", e);
+            std::process::exit(2);
+        }
+        let source_string = match decode_source(bytes, encoding) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Cannot read source file: {}", e);
+                std::process::exit(2);
+            }
+        };
+        sources.push((source_path.display().to_string(), source_string));
+    }
+    sources
+}
+
+/// Runs the debugger on the sources, taking commands from stdin until `quit` or the end of
+/// stdin. An empty line repeats the last command.
+fn debug(options: DebugOptions) -> ! {
+    let DebugOptions {
+        input,
+        byte_input,
+        encoding,
+        source,
+    } = options;
+    let sources = read_sources(source, encoding.as_deref());
+    let instructions = sources
+        .iter()
+        .flat_map(|(_, source_string)| rshyeong::Parser::new(source_string))
+        .collect::<Vec<_>>();
+
+    let input_mode = if byte_input {
+        InputMode::Bytes
+    } else {
+        InputMode::Utf8
+    };
+    let input: Box<dyn Read> = match input {
+        None => Box::new(std::io::empty()),
+        Some(path) => match File::open(path) {
+            Ok(f) => Box::new(f),
+            Err(e) => {
+                eprintln!("Cannot open input file: {}", e);
+                std::process::exit(2);
+            }
+        },
+    };
+    let stdin = HyeongReadStack::with_mode(input, input_mode);
+    let stdout = HyeongWriteStack::new(std::io::stdout());
+    let stderr = HyeongWriteStack::new(std::io::stderr());
+    let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+    let processor = Processor::with_stack_manager(instructions.into_iter(), stacks);
+    let mut debugger = Debugger::new(processor);
+
+    let mut last = None;
+    let mut lines = std::io::stdin().lock().lines();
+    loop {
+        print!("(hyeong) ");
+        let _ = std::io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                eprintln!("Cannot read command: {}", e);
+                std::process::exit(2);
+            }
+            None => break,
+        };
+        let command = match (line.parse::<DebugCommand>(), last) {
+            (Err(DebugCommandError::Empty), Some(last)) => last,
+            (Err(DebugCommandError::Empty), None) => continue,
+            (Err(e), _) => {
+                println!("{}", e);
+                continue;
+            }
+            (Ok(command), _) => command,
+        };
+        if command == DebugCommand::Quit {
+            break;
+        }
+        last = Some(command);
+        let mut stdout = std::io::stdout();
+        let result = debugger
+            .execute(command, &mut stdout)
+            .and_then(|()| debugger.processor_mut().stack_manager_mut().flush());
+        if let Err(e) = result {
+            eprintln!("Error during debugging: {}", e);
+            std::process::exit(3);
+        }
+    }
+    std::process::exit(debugger.exit_code().unwrap_or(0) as i32);
 }
 
 fn main() {
     let Options {
+        command,
         input,
         output,
+        warnings,
+        check,
+        format,
+        dot,
+        explain,
+        emit_json,
+        json,
+        byte_input,
+        rational_output,
+        merge_stderr,
+        fatal_stderr,
+        fatal_nan,
+        max_output,
+        max_eof_reads,
+        max_stack_depth,
+        max_jumps,
+        max_instructions,
+        timeout,
+        listing,
+        encoding,
         source,
     } = Options::parse();
 
-    let mut source = match File::open(source) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("Cannot open source file: {}", e);
-            std::process::exit(2);
+    if let Some(Command::Debug(options)) = command {
+        debug(options);
+    }
+    let sources = read_sources(source, encoding.as_deref());
+
+    if check {
+        let mut failed = false;
+        for (source_path, source_string) in &sources {
+            match rshyeong::validate(source_string) {
+                Ok(info) => println!("{}: {} instructions", source_path, info.instructions),
+                Err(errors) => {
+                    failed = true;
+                    for error in &errors {
+                        let span = error.span.clone();
+                        print_diagnostic(source_path, source_string, span, error, "error");
+                    }
+                }
+            }
         }
-    };
-    let mut source_string = String::new();
-    if let Err(e) = source.read_to_string(&mut source_string) {
-        eprintln!("Cannot read source file: {}", e);
-        std::process::exit(2);
+        std::process::exit(if failed { 1 } else { 0 });
+    }
+
+    if format {
+        for (_, source_string) in &sources {
+            for instr in rshyeong::Parser::new(source_string) {
+                let line = instr
+                    .to_source()
+                    .expect("parsed instructions can always be written back");
+                println!("{}", line);
+            }
+        }
+        return;
+    }
+    if explain {
+        let instructions = sources
+            .iter()
+            .flat_map(|(_, source_string)| rshyeong::Parser::new(source_string));
+        for (position, instr) in instructions.enumerate() {
+            let line = instr
+                .to_source()
+                .expect("parsed instructions can always be written back");
+            let explanation = rshyeong::analysis::explain(&instr);
+            println!("[{}] {}\t{}", position, line, explanation);
+        }
+        return;
+    }
+    if dot {
+        let instructions = sources
+            .iter()
+            .flat_map(|(_, source_string)| rshyeong::Parser::new(source_string))
+            .collect::<Vec<_>>();
+        print!("{}", rshyeong::analysis::to_dot(&instructions));
+        return;
+    }
+    if emit_json {
+        let instructions = sources
+            .iter()
+            .flat_map(|(_, source_string)| rshyeong::Parser::new(source_string))
+            .collect::<Vec<_>>();
+        match write_json(&instructions) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                eprintln!("Cannot write JSON program: {}", e);
+                std::process::exit(2);
+            }
+        }
+        return;
     }
 
-    let stdin: HyeongReadStack<Box<dyn Read>> = if input.as_os_str() == "-" {
-        HyeongReadStack::new(Box::new(std::io::stdin()))
+    // Each file is parsed on its own, so a hangul run can never span two files.
+    let mut instructions = vec![];
+    for (source_path, source_string) in &sources {
+        if json {
+            match read_json(source_string) {
+                Ok(loaded) => instructions.extend(loaded),
+                Err(e) => {
+                    eprintln!("Cannot read source file {}: {}", source_path, e);
+                    std::process::exit(2);
+                }
+            }
+            continue;
+        }
+        let mut parser = rshyeong::Parser::new(source_string);
+        let start = instructions.len();
+        instructions.extend(parser.by_ref());
+        if warnings {
+            for error in parser.errors() {
+                let span = error.span.clone();
+                print_diagnostic(source_path, source_string, span, error, "warning");
+            }
+            for warning in parser.warnings() {
+                let span = warning.span.clone();
+                print_diagnostic(source_path, source_string, span, warning, "warning");
+            }
+            for lint in rshyeong::analysis::lint(&instructions[start..]) {
+                match &lint.span {
+                    Some(span) => {
+                        let span = span.bytes.clone();
+                        print_diagnostic(source_path, source_string, span, &lint, "warning");
+                    }
+                    None => eprintln!("{}: warning: {}", source_path, lint),
+                }
+            }
+        }
+    }
+
+    let input_mode = if byte_input {
+        InputMode::Bytes
+    } else {
+        InputMode::Utf8
+    };
+    let mut stdin: HyeongReadStack<Box<dyn Read>> = if input.as_os_str() == "-" {
+        HyeongReadStack::with_mode(Box::new(std::io::stdin()), input_mode)
     } else {
         let file = match File::open(input) {
             Ok(f) => f,
@@ -48,11 +474,11 @@ fn main() {
                 std::process::exit(2);
             }
         };
-        HyeongReadStack::new(Box::new(file))
+        HyeongReadStack::with_mode(Box::new(file), input_mode)
     };
 
-    let stdout: HyeongWriteStack<Box<dyn Write>> = if output.as_os_str() == "-" {
-        HyeongWriteStack::new(Box::new(std::io::stdout()))
+    let stdout: Box<dyn Write> = if output.as_os_str() == "-" {
+        Box::new(std::io::stdout())
     } else {
         let file = match File::create(output) {
             Ok(f) => f,
@@ -61,16 +487,56 @@ fn main() {
                 std::process::exit(2);
             }
         };
-        HyeongWriteStack::new(Box::new(BufWriter::new(file)))
+        Box::new(BufWriter::new(file))
+    };
+    let (stdout, stderr): (Box<dyn Write>, Box<dyn Write>) = if merge_stderr {
+        let shared = SharedWriter::new(stdout);
+        (Box::new(shared.clone()), Box::new(shared))
+    } else {
+        (stdout, Box::new(std::io::stderr()))
     };
 
-    let stderr = HyeongWriteStack::new(std::io::stderr());
+    stdin.set_eof_limit(max_eof_reads);
+    let mut stdout = HyeongWriteStack::new(stdout);
+    let mut stderr = HyeongWriteStack::new(stderr);
+    stdout.set_limit(max_output);
+    if rational_output {
+        stdout.set_mode(OutputMode::Rational);
+        stderr.set_mode(OutputMode::Rational);
+    }
 
-    let stacks = StackManager::from_stacks(stdin, stdout, stderr);
-    let parser = rshyeong::Parser::new(&source_string);
-    let processor = Processor::with_stack_manager(parser, stacks);
+    let mut stacks = StackManager::from_stacks(stdin, stdout, stderr);
+    stacks.set_depth_limit(max_stack_depth);
+    stacks.set_fatal_stderr(fatal_stderr);
+    stacks.set_fatal_nan(fatal_nan);
+    let mut processor = Processor::with_stack_manager(instructions.into_iter(), stacks);
+    processor.set_max_jumps(max_jumps);
+    processor.set_fuel(max_instructions);
+    if let Some(timeout) = timeout {
+        let timeout = match Duration::try_from_secs_f64(timeout) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                eprintln!("Invalid timeout: {}", e);
+                std::process::exit(2);
+            }
+        };
+        processor.set_deadline(Instant::now().checked_add(timeout));
+    }
+    if listing {
+        processor.enable_listing(std::io::stderr());
+    }
 
-    let (exit_code, err) = processor.run();
+    let exit_code = loop {
+        if let Some(code) = processor.advance() {
+            break code;
+        }
+    };
+    if processor.stack_manager().stopped_on_nan() {
+        if let Some(position) = processor.exit_instruction() {
+            eprintln!("{}", rshyeong::Error::UnexpectedNan { position });
+        }
+    }
+    let err = processor.stack_manager_mut().flush();
     if let Err(e) = err {
         eprintln!(
             "Error during flushing: {}\nExit code was: {}",
@@ -79,5 +545,11 @@ fn main() {
         );
         std::process::exit(3);
     }
+    if exit_code == OUTPUT_LIMIT_EXIT_CODE {
+        eprintln!("Output limit exceeded");
+    }
+    if exit_code == TIMEOUT_EXIT_CODE {
+        eprintln!("Time limit exceeded");
+    }
     std::process::exit(exit_code as i32);
 }
@@ -1,9 +1,14 @@
-use super::structure::{HeartTree, Instruction, Operation};
+use super::structure::{HeartTree, Instruction, LineColumn, Operation, Span};
 use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::iter::Peekable;
+use std::ops::Range;
 use std::str;
 
+/// The syllable an operation starts with. `혀`, `하` and `흐` need a matching end syllable;
+/// the rest are complete operations by themselves.
 #[derive(Copy, Clone, Debug, PartialEq)]
-enum HangulStartType {
+pub enum HangulStartType {
     Hyeo,
     Ha,
     Heu,
@@ -16,7 +21,7 @@ enum HangulStartType {
 }
 
 impl HangulStartType {
-    fn from_char(c: char) -> Option<Self> {
+    pub fn from_char(c: char) -> Option<Self> {
         use self::HangulStartType::*;
         match c {
             '혀' => Some(Hyeo),
@@ -32,10 +37,22 @@ impl HangulStartType {
         }
     }
 
-    fn is_self_ending(&self) -> bool {
+    pub fn is_self_ending(&self) -> bool {
         use self::HangulStartType::*;
         !matches!(self, Hyeo | Ha | Heu)
     }
+
+    /// Index of `혀`, `하` or `흐` among the starts which need an end syllable, in that order.
+    /// `None` for the self-ending ones.
+    fn run_index(self) -> Option<usize> {
+        use self::HangulStartType::*;
+        match self {
+            Hyeo => Some(0),
+            Ha => Some(1),
+            Heu => Some(2),
+            _ => None,
+        }
+    }
 }
 
 impl From<HangulStartType> for char {
@@ -64,7 +81,7 @@ pub enum Token {
     QuestionMark,
 }
 
-const HEART_MARKS: [char; 11] = [
+pub(crate) const HEART_MARKS: [char; 11] = [
     '\u{2665}',
     '\u{2764}',
     '\u{1f495}',
@@ -78,6 +95,33 @@ const HEART_MARKS: [char; 11] = [
     '\u{1f49d}',
 ];
 
+const RECOGNIZED_TOKENS: [(char, Token); 18] = [
+    ('.', Token::Dot),
+    ('\u{2026}', Token::ThreeDots),
+    ('\u{22ee}', Token::ThreeDots),
+    ('\u{22ef}', Token::ThreeDots),
+    ('\u{2661}', Token::ReturnHeart),
+    ('!', Token::ExclamationMark),
+    ('?', Token::QuestionMark),
+    ('\u{2665}', Token::Heart(0)),
+    ('\u{2764}', Token::Heart(1)),
+    ('\u{1f495}', Token::Heart(2)),
+    ('\u{1f496}', Token::Heart(3)),
+    ('\u{1f497}', Token::Heart(4)),
+    ('\u{1f498}', Token::Heart(5)),
+    ('\u{1f499}', Token::Heart(6)),
+    ('\u{1f49a}', Token::Heart(7)),
+    ('\u{1f49b}', Token::Heart(8)),
+    ('\u{1f49c}', Token::Heart(9)),
+    ('\u{1f49d}', Token::Heart(10)),
+];
+
+/// Every codepoint recognized as a token, with the token it is read as. Any other codepoint
+/// outside hangul runs is ignored.
+pub fn recognized_tokens() -> &'static [(char, Token)] {
+    &RECOGNIZED_TOKENS
+}
+
 impl Token {
     pub fn from_char(c: char) -> Option<Self> {
         match c {
@@ -89,66 +133,400 @@ impl Token {
             _ => HEART_MARKS.iter().position(|&i| i == c).map(Token::Heart),
         }
     }
+
+    fn len_utf8(&self) -> usize {
+        match *self {
+            Token::Dot | Token::ExclamationMark | Token::QuestionMark => 1,
+            Token::ThreeDots | Token::ReturnHeart => 3,
+            Token::Heart(id) => HEART_MARKS[id].len_utf8(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// A `혀`, `하` or `흐` that is never followed by a matching end syllable.
+    UnterminatedHangul(char),
+    /// A token before the first instruction, which belongs to nothing. Only reported by strict
+    /// parsers.
+    DanglingToken(char),
+    /// A `?` or `!` with no heart on one of its sides, which compares against nothing. Only
+    /// reported by strict parsers.
+    MissingOperand(char),
+}
+
+/// A malformed construct the parser skipped. `span` is a byte range into the source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    pub span: Range<usize>,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::UnterminatedHangul(c) => {
+                write!(f, "unterminated hangul run starting with '{}'", c)
+            }
+            ParseErrorKind::DanglingToken(c) => {
+                write!(f, "'{}' comes before any instruction", c)
+            }
+            ParseErrorKind::MissingOperand(c) => {
+                write!(f, "'{}' is missing a heart on one of its sides", c)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParseWarningKind {
+    /// A start syllable inside the hangul run of another start, which became part of that run
+    /// instead of starting an instruction of its own.
+    AbsorbedStart { run: char, absorbed: char },
+}
+
+/// A construct which parsed, but likely not as intended. `span` is a byte range into the
+/// source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseWarning {
+    pub kind: ParseWarningKind,
+    pub span: Range<usize>,
+}
+
+impl Display for ParseWarning {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.kind {
+            ParseWarningKind::AbsorbedStart { run, absorbed } => write!(
+                f,
+                "'{}' is part of the hangul run starting with '{}'",
+                absorbed, run
+            ),
+        }
+    }
+}
+
+/// Summary of a program which parsed without errors.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramInfo {
+    pub instructions: usize,
+    /// Bytes of source which were neither instructions nor tokens attached to them.
+    pub discarded_bytes: usize,
+}
+
+/// Adds `error` to `errors`, keeping them sorted by span. Errors aren't always found in source
+/// order, e.g. an unterminated start past the tokens of an instruction is found before those
+/// tokens are checked, but they mostly are, so this is cheap.
+fn report(errors: &mut Vec<ParseError>, error: ParseError) {
+    let key = |e: &ParseError| (e.span.start, e.span.end);
+    let index = errors.partition_point(|e| key(e) <= key(&error));
+    errors.insert(index, error);
+}
+
+/// Parses all of `source` without running it, failing if any malformed construct is found.
+pub fn validate(source: &str) -> Result<ProgramInfo, Vec<ParseError>> {
+    let mut info = ProgramInfo {
+        instructions: 0,
+        discarded_bytes: 0,
+    };
+    let mut coverage = Parser::with_coverage(source);
+    for (_, discarded) in &mut coverage {
+        info.instructions += 1;
+        info.discarded_bytes += discarded;
+    }
+    let parser = coverage.into_inner();
+    if parser.errors().is_empty() {
+        Ok(info)
+    } else {
+        Err(parser.errors().to_vec())
+    }
+}
+
+/// Whether `c` is a precomposed hangul syllable, from `가` to `힣`. By default, each of these
+/// inside a hangul run adds to its hangul count.
+pub fn is_hangul_syllable(c: char) -> bool {
+    ('가'..='힣').contains(&c)
+}
+
+/// Invisible formatting characters skipped by default: zero-width space, zero-width non-joiner,
+/// zero-width joiner, soft hyphen and byte order mark. These often sneak into copy-pasted code.
+pub const FORMAT_CHARS: &[char] = &['\u{200b}', '\u{200c}', '\u{200d}', '\u{ad}', '\u{feff}'];
+
+// Where the parser last located a byte offset, to find later ones without rescanning
+struct Cursor {
+    byte: usize,
+    char: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Cursor {
+            byte: 0,
+            char: 0,
+            line: 1,
+            column: 1,
+        }
+    }
 }
 
 pub struct Parser<'a> {
+    source: &'a str,
+    ignored: &'a [char],
+    counts: fn(char) -> bool,
     code: str::Chars<'a>,
-    operation_cache: Option<Operation>,
+    operation_cache: Option<(Operation, Range<usize>)>,
     token_cache: VecDeque<Token>,
+    // Byte offsets of the tokens in `token_cache`
+    token_starts: Vec<usize>,
+    // End of the last token in `token_cache`
+    tokens_end: Option<usize>,
+    strict: bool,
+    cursor: Cursor,
+    discarded_cache: usize,
+    // Whether the rest of the code has no end syllable for `혀`, `하` and `흐` respectively
+    exhausted: [bool; 3],
+    errors: Vec<ParseError>,
+    warnings: Vec<ParseWarning>,
+    dangling: Vec<(Token, Range<usize>)>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(code: &'a str) -> Self {
+        Parser::with_ignored(code, FORMAT_CHARS)
+    }
+
+    /// Like `new`, but skips the characters in `ignored` instead of `FORMAT_CHARS`. Ignored
+    /// characters are never counted as hangul nor as discarded prose.
+    pub fn with_ignored(code: &'a str, ignored: &'a [char]) -> Self {
+        Parser::with_counter(code, ignored, is_hangul_syllable)
+    }
+
+    /// Like `with_ignored`, but counts only the characters satisfying `counts` towards the
+    /// hangul count of a run, between its start and end syllables. The start and end count
+    /// either way. `new` counts every hangul syllable, as `is_hangul_syllable` does; other
+    /// interpreters may count only filler syllables like `어`.
+    pub fn with_counter(code: &'a str, ignored: &'a [char], counts: fn(char) -> bool) -> Self {
         let mut parser = Parser {
+            source: code,
+            ignored,
+            counts,
             code: code.chars(),
             operation_cache: None,
             token_cache: VecDeque::new(),
+            token_starts: vec![],
+            tokens_end: None,
+            strict: false,
+            cursor: Cursor::default(),
+            discarded_cache: 0,
+            exhausted: [false; 3],
+            errors: vec![],
+            warnings: vec![],
+            dangling: vec![],
         };
         // First run
-        let hangul = parser.parse_hangul();
+        let (hangul, skipped) = parser.parse_hangul();
         parser.operation_cache = hangul;
+        // Tokens before the first instruction don't belong to anything
+        let token_bytes = parser
+            .token_cache
+            .iter()
+            .map(Token::len_utf8)
+            .sum::<usize>();
+        parser.discarded_cache = skipped + token_bytes;
+        // They are the first tokens in the source, since nothing else is consumed before the
+        // first instruction
+        parser.dangling = code
+            .char_indices()
+            .filter_map(|(i, c)| Token::from_char(c).map(|t| (t, i..(i + c.len_utf8()))))
+            .take(parser.token_cache.len())
+            .collect();
         parser
     }
 
-    fn parse_hangul(&mut self) -> Option<Operation> {
+    /// Like `new`, but also reports constructs which parse yet likely aren't intended as errors:
+    /// tokens before the first instruction, and `?` or `!` with no heart on one of their sides.
+    /// The instructions yielded are the same.
+    pub fn new_strict(code: &'a str) -> Self {
+        let mut parser = Parser::new(code);
+        parser.strict = true;
+        // Unterminated runs before the first instruction were found already
+        for (_, span) in &parser.dangling {
+            report(
+                &mut parser.errors,
+                ParseError {
+                    kind: ParseErrorKind::DanglingToken(code[span.clone()].chars().next().unwrap()),
+                    span: span.clone(),
+                },
+            );
+        }
+        parser
+    }
+
+    /// Tokens which don't belong to any instruction, with their byte spans.
+    ///
+    /// Tokens attach to the instruction before them, so these are the ones before the first
+    /// instruction (or every token, if there is no instruction). Trailing tokens after the last
+    /// instruction belong to it and are not dangling: `형. ♥` yields `형.` with the heart, so
+    /// nothing at the end of the source is dropped. These are known as soon as the parser is
+    /// made, before iterating.
+    pub fn dangling(&self) -> &[(Token, Range<usize>)] {
+        &self.dangling
+    }
+
+    /// Like `new`, but also yields how many bytes of source before each instruction were
+    /// discarded as prose, i.e. were neither part of an instruction nor a token attached to one.
+    pub fn with_coverage(code: &'a str) -> Coverage<'a> {
+        Coverage {
+            inner: Parser::new(code),
+        }
+    }
+
+    /// Malformed constructs found so far, sorted by span. These are skipped, so parsing never
+    /// fails.
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
+    /// Constructs found so far which parsed, but likely not as intended.
+    ///
+    /// A `혀`, `하` or `흐` runs up to the next matching end syllable whatever comes between, so
+    /// in `하 형 앙` the `형` is just one of three hangul of a single `항`. Start syllables
+    /// swallowed like that are reported here. A start with no end at all is an error instead.
+    pub fn warnings(&self) -> &[ParseWarning] {
+        &self.warnings
+    }
+
+    /// Bytes of source not read yet, for showing progress. To find the tokens of an instruction
+    /// the parser reads up to the start of the next one, so this runs slightly ahead of the
+    /// instructions yielded.
+    pub fn remaining_bytes(&self) -> usize {
+        self.code.as_str().len()
+    }
+
+    fn offset(&self) -> usize {
+        self.source.len() - self.code.as_str().len()
+    }
+
+    /// Finds the char offset and line and column of the byte offset `byte`, which must not be
+    /// before the last one located.
+    fn locate(&mut self, byte: usize) -> (usize, LineColumn) {
+        let cursor = &mut self.cursor;
+        for c in self.source[cursor.byte..byte].chars() {
+            cursor.char += 1;
+            if c == '\n' {
+                cursor.line += 1;
+                cursor.column = 1;
+            } else {
+                cursor.column += 1;
+            }
+        }
+        cursor.byte = byte;
+        let location = LineColumn {
+            line: cursor.line,
+            column: cursor.column,
+        };
+        (cursor.char, location)
+    }
+
+    fn make_span(&mut self, bytes: Range<usize>) -> Span {
+        let (start_char, start) = self.locate(bytes.start);
+        let (end_char, end) = self.locate(bytes.end);
+        Span {
+            bytes,
+            chars: start_char..end_char,
+            start,
+            end,
+        }
+    }
+
+    /// Parses the next operation with its byte span, collecting tokens on the way. Also returns
+    /// how many bytes were skipped without being a token.
+    fn parse_hangul(&mut self) -> (Option<(Operation, Range<usize>)>, usize) {
         self.token_cache.clear();
+        self.token_starts.clear();
+        self.tokens_end = None;
+        let mut skipped = 0;
         loop {
             let mut start = None;
-            for c in &mut self.code {
+            while let Some(c) = self.code.next() {
                 if "형항핫흣흡흑혀하흐".contains(c) {
                     start = HangulStartType::from_char(c);
                     break;
                 }
                 if let Some(token) = Token::from_char(c) {
                     self.token_cache.push_back(token);
+                    self.token_starts.push(self.offset() - c.len_utf8());
+                    self.tokens_end = Some(self.offset());
+                } else if !self.ignored.contains(&c) {
+                    skipped += c.len_utf8();
                 }
             }
             let start = match start {
                 Some(item) => item,
                 None => {
-                    return None;
+                    return (None, skipped);
                 }
             };
-            if start.is_self_ending() {
-                return Some(Operation::from_chars(start.into(), None, 1));
-            }
-            let mut temp_iter = self.code.clone();
-            if let Some((count, c)) = Parser::find_matching_end(start, &mut temp_iter) {
-                self.code = temp_iter;
-                let length = count + 1;
-                return Some(Operation::from_chars(start.into(), Some(c), length as u64));
+            let op_start = self.offset() - char::from(start).len_utf8();
+            let run_index = match start.run_index() {
+                Some(index) => index,
+                None => {
+                    let op = Operation::from_chars(start.into(), None, 1);
+                    return (Some((op, op_start..self.offset())), skipped);
+                }
+            };
+            // A failed lookahead scans to the end of the code, so it can't succeed later either.
+            // Remembering that keeps runs of unterminated starts linear.
+            if !self.exhausted[run_index] {
+                let mut temp_iter = self.code.clone();
+                if let Some((count, c)) =
+                    Parser::find_matching_end(start, &mut temp_iter, self.ignored, self.counts)
+                {
+                    let run_start = self.offset();
+                    self.code = temp_iter;
+                    let run_end = self.offset() - c.len_utf8();
+                    let run = char::from(start);
+                    self.warnings.extend(
+                        self.source[run_start..run_end]
+                            .char_indices()
+                            .filter(|&(_, c)| HangulStartType::from_char(c).is_some())
+                            .map(|(i, absorbed)| ParseWarning {
+                                kind: ParseWarningKind::AbsorbedStart { run, absorbed },
+                                span: (run_start + i)..(run_start + i + absorbed.len_utf8()),
+                            }),
+                    );
+                    let length = count + 1;
+                    let op = Operation::from_chars(start.into(), Some(c), length as u64);
+                    return (Some((op, op_start..self.offset())), skipped);
+                }
+                self.exhausted[run_index] = true;
             }
+            let start_char = char::from(start);
+            let end = self.offset();
+            skipped += start_char.len_utf8();
+            report(
+                &mut self.errors,
+                ParseError {
+                    kind: ParseErrorKind::UnterminatedHangul(start_char),
+                    span: (end - start_char.len_utf8())..end,
+                },
+            );
         }
     }
 
     fn find_matching_end<T: Iterator<Item = char>>(
         start: HangulStartType,
         iter: &mut T,
+        ignored: &[char],
+        counts: fn(char) -> bool,
     ) -> Option<(usize, char)> {
         let mut cnt = 0;
         for c in iter {
-            if ('가'..='힣').contains(&c) {
-                cnt += 1;
+            if ignored.contains(&c) {
+                continue;
             }
             let end = match start {
                 HangulStartType::Hyeo => '엉' == c,
@@ -157,47 +535,174 @@ impl<'a> Parser<'a> {
                 _ => false,
             };
             if end {
-                return Some((cnt, c));
+                return Some((cnt + 1, c));
+            }
+            if counts(c) {
+                cnt += 1;
             }
         }
         None
     }
 }
 
-impl<'a> Iterator for Parser<'a> {
-    type Item = Instruction;
+pub struct Coverage<'a> {
+    inner: Parser<'a>,
+}
+
+impl<'a> Coverage<'a> {
+    pub fn into_inner(self) -> Parser<'a> {
+        self.inner
+    }
+}
+
+impl<'a> Iterator for Coverage<'a> {
+    type Item = (Instruction, usize);
     fn next(&mut self) -> Option<Self::Item> {
-        let op = match self.operation_cache {
+        self.inner.next_with_discarded()
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn next_with_discarded(&mut self) -> Option<(Instruction, usize)> {
+        let (op, op_span) = match self.operation_cache.take() {
             Some(op) => op,
             None => {
                 return None;
             }
         };
-        let next_op = self.parse_hangul();
+        let discarded = self.discarded_cache;
+        let (next_op, skipped) = self.parse_hangul();
         self.operation_cache = next_op;
+        self.discarded_cache = skipped;
 
-        // dots
-        let tokens = self
-            .token_cache
+        // Tokens attach to the instruction even past prose, so it spans up to the last of them
+        let end = self.tokens_end.unwrap_or(op_span.end);
+        let span = self.make_span(op_span.start..end);
+        let tokens = self.token_cache.make_contiguous();
+        if self.strict {
+            for i in missing_operands(tokens) {
+                let c = if tokens[i] == Token::QuestionMark {
+                    '?'
+                } else {
+                    '!'
+                };
+                let start = self.token_starts[i];
+                report(
+                    &mut self.errors,
+                    ParseError {
+                        kind: ParseErrorKind::MissingOperand(c),
+                        span: start..(start + 1),
+                    },
+                );
+            }
+        }
+        let instr = assemble(op, tokens).with_span(span);
+        Some((instr, discarded))
+    }
+}
+
+/// Indices of the `?` and `!` in `tokens` with no heart between them and the previous or next
+/// separator.
+fn missing_operands(tokens: &[Token]) -> Vec<usize> {
+    let is_separator =
+        |token: &Token| matches!(token, Token::QuestionMark | Token::ExclamationMark);
+    let has_heart = |group: &[Token]| {
+        group
             .iter()
-            .take_while(|token| matches!(token, Token::Dot | Token::ThreeDots));
-        let dots = tokens.fold(0, |i, token| match *token {
-            Token::Dot => i + 1,
-            Token::ThreeDots => i + 3,
-            _ => i,
-        });
-        // hearts
+            .any(|token| matches!(token, Token::Heart(_) | Token::ReturnHeart))
+    };
+    let separators = tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, token)| is_separator(token))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    let mut missing = vec![];
+    for (n, &i) in separators.iter().enumerate() {
+        let before = if n == 0 { 0 } else { separators[n - 1] + 1 };
+        let after = separators.get(n + 1).copied().unwrap_or(tokens.len());
+        if !has_heart(&tokens[before..i]) || !has_heart(&tokens[(i + 1)..after]) {
+            missing.push(i);
+        }
+    }
+    missing
+}
+
+/// Builds an instruction from its operation and the tokens after it.
+fn assemble(op: Operation, tokens: &[Token]) -> Instruction {
+    // Only the leading dots count
+    let dots = tokens
+        .iter()
+        .map_while(|token| match *token {
+            Token::Dot => Some(1),
+            Token::ThreeDots => Some(3),
+            _ => None,
+        })
+        .sum();
+    Instruction::new(op, dots, HeartTree::from_tokens(tokens))
+}
+
+/// What a lexer sees in source code: an operation spelled in hangul, or a token.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Event {
+    Operation(Operation),
+    Token(Token),
+}
+
+impl<'a> Parser<'a> {
+    /// Builds instructions from already lexed `events`, for front-ends with their own lexer.
+    /// Tokens belong to the operation before them; those before the first operation are
+    /// dropped.
+    pub fn from_tokens<I: IntoIterator<Item = Event>>(events: I) -> Assembler<I::IntoIter> {
+        Assembler {
+            events: events.into_iter().peekable(),
+            tokens: vec![],
+        }
+    }
+}
+
+/// Iterator over the instructions built from lexer events. See `Parser::from_tokens`.
+pub struct Assembler<I: Iterator<Item = Event>> {
+    events: Peekable<I>,
+    tokens: Vec<Token>,
+}
+
+impl<I: Iterator<Item = Event>> Iterator for Assembler<I> {
+    type Item = Instruction;
+    fn next(&mut self) -> Option<Self::Item> {
+        let op = loop {
+            match self.events.next()? {
+                Event::Operation(op) => break op,
+                Event::Token(_) => {}
+            }
+        };
+        self.tokens.clear();
+        while let Some(&Event::Token(token)) = self.events.peek() {
+            self.tokens.push(token);
+            self.events.next();
+        }
+        Some(assemble(op, &self.tokens))
+    }
+}
+
+impl HeartTree {
+    /// Builds the heart tree of an instruction from the tokens after it. Dots are ignored.
+    ///
+    /// `?` separates `LessThan` operands and binds looser than `!`, which separates `Equals`
+    /// operands; both group to the right.
+    pub fn from_tokens(tokens: &[Token]) -> HeartTree {
+        // Only the first heart of each `!`/`?`-delimited group counts, whether it is a numbered
+        // heart or the return heart `♡`; any hearts after it in the same group are ignored.
+        // `tree` never runs short of operands: each `!` pushes a group and counts itself in
+        // `op_count`, so when the `Equals` are folded there is one more group above the last
+        // `?` than there are `!`s, whatever order the tokens come in.
         let mut current_heart = None;
         let mut tree = vec![];
         let mut op_count = 0;
-        let hearts = self
-            .token_cache
-            .iter()
-            .filter(|token| !matches!(token, Token::Dot | Token::ThreeDots));
-        for token in hearts {
+        for token in tokens {
             match *token {
                 Token::Heart(id) => {
-                    current_heart = current_heart.or_else(|| Some(HeartTree::Heart(id as u64)));
+                    current_heart = current_heart.or(Some(HeartTree::Heart(id as u64)));
                 }
                 Token::ReturnHeart => {
                     current_heart = current_heart.or(Some(HeartTree::Return));
@@ -217,7 +722,7 @@ impl<'a> Iterator for Parser<'a> {
                     }
                     op_count = 0;
                 }
-                _ => {}
+                Token::Dot | Token::ThreeDots => {}
             }
         }
         tree.push(current_heart.unwrap_or(HeartTree::Nil));
@@ -231,11 +736,14 @@ impl<'a> Iterator for Parser<'a> {
             let lhs = tree.pop().unwrap();
             tree.push(HeartTree::LessThan(Box::new(lhs), Box::new(rhs)));
         }
-        Some(Instruction::new(
-            op,
-            dots,
-            tree.pop().unwrap_or(HeartTree::Nil),
-        ))
+        tree.pop().unwrap_or(HeartTree::Nil)
+    }
+}
+
+impl<'a> Iterator for Parser<'a> {
+    type Item = Instruction;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_with_discarded().map(|(instr, _)| instr)
     }
 }
 
@@ -296,7 +804,7 @@ mod tests {
     }
 
     mod token {
-        use super::super::{Token, HEART_MARKS};
+        use super::super::{recognized_tokens, Token, HEART_MARKS};
 
         #[test]
         fn from_char() {
@@ -322,6 +830,113 @@ mod tests {
             assert_eq!(Token::from_char('\u{2765}'), None);
             assert_eq!(Token::from_char('\u{1f49e}'), None);
         }
+
+        #[test]
+        fn recognized() {
+            let accepted = (0..=0x10ffff)
+                .filter_map(std::char::from_u32)
+                .filter_map(|c| Token::from_char(c).map(|t| (c, t)))
+                .collect::<Vec<_>>();
+            let mut listed = recognized_tokens().to_vec();
+            listed.sort_by_key(|&(c, _)| c);
+            assert_eq!(accepted, listed);
+        }
+    }
+
+    mod heart_tree {
+        use super::super::super::structure::HeartTree;
+        use super::super::Token;
+
+        fn leaf(id: u64) -> Box<HeartTree> {
+            Box::new(HeartTree::Heart(id))
+        }
+
+        fn count_leaves(tree: &HeartTree) -> usize {
+            match tree {
+                HeartTree::LessThan(l, r) | HeartTree::Equals(l, r) => {
+                    count_leaves(l) + count_leaves(r)
+                }
+                _ => 1,
+            }
+        }
+
+        #[test]
+        fn adversarial_sequences() {
+            use self::Token::*;
+
+            // Every sequence of up to six tokens builds a tree with one leaf per group
+            let alphabet = [ExclamationMark, QuestionMark, Heart(0), ReturnHeart, Dot];
+            let mut tokens = vec![];
+            for len in 0..=6u32 {
+                for mut n in 0..alphabet.len().pow(len) {
+                    tokens.clear();
+                    for _ in 0..len {
+                        tokens.push(alphabet[n % alphabet.len()]);
+                        n /= alphabet.len();
+                    }
+                    let tree = HeartTree::from_tokens(&tokens);
+                    let groups = tokens
+                        .iter()
+                        .filter(|t| matches!(t, ExclamationMark | QuestionMark))
+                        .count()
+                        + 1;
+                    assert_eq!(count_leaves(&tree), groups, "{:?}", tokens);
+                }
+            }
+
+            let tree = HeartTree::from_tokens(&[QuestionMark; 3]);
+            assert_eq!(tree.depth(), 3);
+            assert_eq!(tree.heart_count(), 0);
+            let tree = HeartTree::from_tokens(&[ExclamationMark, QuestionMark, QuestionMark]);
+            assert_eq!(count_leaves(&tree), 4);
+            // Heart-only sources parse too, with or without an instruction to attach to
+            assert_eq!(super::super::Parser::new("? ? ? ! ?!♡").count(), 0);
+            assert_eq!(super::super::Parser::new("형? ? ? ! ?!! ♡?").count(), 1);
+        }
+
+        #[test]
+        fn from_tokens() {
+            use self::Token::*;
+
+            assert_eq!(HeartTree::from_tokens(&[]), HeartTree::Nil);
+            assert_eq!(HeartTree::from_tokens(&[Dot, ThreeDots]), HeartTree::Nil);
+            assert_eq!(
+                HeartTree::from_tokens(&[Heart(1), Dot, Heart(2), ReturnHeart]),
+                HeartTree::Heart(1)
+            );
+            // ♥0 ! ♥1 ? ♥2 ! ♥3 ! ♥4
+            let tokens = [
+                Heart(0),
+                ExclamationMark,
+                Heart(1),
+                QuestionMark,
+                Heart(2),
+                ExclamationMark,
+                Heart(3),
+                ExclamationMark,
+                Heart(4),
+            ];
+            let expected = HeartTree::LessThan(
+                Box::new(HeartTree::Equals(leaf(0), leaf(1))),
+                Box::new(HeartTree::Equals(
+                    leaf(2),
+                    Box::new(HeartTree::Equals(leaf(3), leaf(4))),
+                )),
+            );
+            assert_eq!(HeartTree::from_tokens(&tokens), expected);
+            // ? ♡ ?
+            let expected = HeartTree::LessThan(
+                Box::new(HeartTree::Nil),
+                Box::new(HeartTree::LessThan(
+                    Box::new(HeartTree::Return),
+                    Box::new(HeartTree::Nil),
+                )),
+            );
+            assert_eq!(
+                HeartTree::from_tokens(&[QuestionMark, ReturnHeart, QuestionMark]),
+                expected
+            );
+        }
     }
 
     mod parser {
@@ -423,6 +1038,286 @@ mod tests {
             assert_instruction!(parser);
         }
 
+        #[test]
+        fn unterminated_errors() {
+            use super::super::{ParseError, ParseErrorKind};
+
+            let mut parser = Parser::new("혀형하앙... 흐");
+            assert_instruction!(parser, make_instruction!('형', 1, 0, _));
+            assert_instruction!(parser, make_instruction!('항', 2, 3, _));
+            assert_instruction!(parser);
+            assert_eq!(
+                parser.errors(),
+                &[
+                    ParseError {
+                        kind: ParseErrorKind::UnterminatedHangul('혀'),
+                        span: 0..3,
+                    },
+                    ParseError {
+                        kind: ParseErrorKind::UnterminatedHangul('흐'),
+                        span: 16..19,
+                    },
+                ]
+            );
+        }
+
+        #[test]
+        fn strict_errors() {
+            use super::super::{ParseError, ParseErrorKind};
+
+            let source = "♥ 형.?♥ 항!♡ 흑♥?♡!💖 흣??♥ 흐";
+            let error = |kind, span| ParseError { kind, span };
+            let mut parser = Parser::new_strict(source);
+            let instructions = parser.by_ref().collect::<Vec<_>>();
+            assert_eq!(instructions, Parser::new(source).collect::<Vec<_>>());
+            // Sorted by span, though the last `흐` is found unterminated while looking for the
+            // end of the tokens of `흣`, before those are checked
+            assert_eq!(
+                parser.errors(),
+                &[
+                    error(ParseErrorKind::DanglingToken('♥'), 0..3),
+                    error(ParseErrorKind::MissingOperand('?'), 8..9),
+                    error(ParseErrorKind::MissingOperand('!'), 16..17),
+                    error(ParseErrorKind::MissingOperand('?'), 40..41),
+                    error(ParseErrorKind::MissingOperand('?'), 41..42),
+                    error(ParseErrorKind::UnterminatedHangul('흐'), 46..49),
+                ]
+            );
+
+            let mut parser = Parser::new(source);
+            parser.by_ref().for_each(drop);
+            assert_eq!(parser.errors().len(), 1);
+        }
+
+        #[test]
+        fn absorbed_start_warnings() {
+            use super::super::{ParseWarning, ParseWarningKind};
+
+            let mut parser = Parser::new("하 형 앙 흐읏 혀흑항엉 혀형");
+            assert_instruction!(parser, make_instruction!('항', 3, 0, _));
+            assert_instruction!(parser, make_instruction!('흣', 2, 0, _));
+            assert_instruction!(parser, make_instruction!('형', 4, 0, _));
+            assert_instruction!(parser, make_instruction!('형', 1, 0, _));
+            assert_instruction!(parser);
+            let absorbed = |run, absorbed, span| ParseWarning {
+                kind: ParseWarningKind::AbsorbedStart { run, absorbed },
+                span,
+            };
+            assert_eq!(
+                parser.warnings(),
+                &[
+                    absorbed('하', '형', 4..7),
+                    absorbed('혀', '흑', 22..25),
+                    absorbed('혀', '항', 25..28),
+                ]
+            );
+            // An unterminated start is an error, not a warning
+            assert_eq!(parser.errors().len(), 1);
+        }
+
+        #[test]
+        fn coverage() {
+            let mut parser = Parser::with_coverage("abc 형. xy 항 흐 ..흑");
+            assert_eq!(parser.next(), Some((make_instruction!('형', 1, 1, _), 4)));
+            assert_eq!(parser.next(), Some((make_instruction!('항', 1, 2, _), 4)));
+            assert_eq!(parser.next(), Some((make_instruction!('흑', 1, 0, _), 5)));
+            assert_eq!(parser.next(), None);
+
+            let mut parser = Parser::with_coverage("..♥ 혀엉");
+            assert_eq!(parser.next(), Some((make_instruction!('형', 2, 0, _), 6)));
+            assert_eq!(parser.next(), None);
+        }
+
+        #[test]
+        fn validate() {
+            use super::super::{validate, ParseError, ParseErrorKind, ProgramInfo};
+
+            assert_eq!(
+                validate("혀엉... 흑. 흐읏..... 하아아앙..."),
+                Ok(ProgramInfo {
+                    instructions: 4,
+                    discarded_bytes: 3,
+                })
+            );
+            assert_eq!(
+                validate("형 혀 흑"),
+                Err(vec![ParseError {
+                    kind: ParseErrorKind::UnterminatedHangul('혀'),
+                    span: 4..7,
+                }])
+            );
+        }
+
+        #[test]
+        fn many_unterminated() {
+            // Would take quadratic time if every start scanned to the end again
+            let code = "혀하흐".repeat(100_000) + "형";
+            let mut parser = Parser::new(&code);
+            assert_instruction!(parser, make_instruction!('형', 1, 0, _));
+            assert_instruction!(parser);
+            assert_eq!(parser.errors().len(), 300_000);
+        }
+
+        #[test]
+        fn format_chars() {
+            let mut parser = Parser::new("\u{feff}하\u{200b}아\u{200d}앙.\u{ad}.");
+            assert_instruction!(parser, make_instruction!('항', 3, 2, _));
+            assert_instruction!(parser);
+
+            let mut parser = Parser::with_coverage("\u{200b}흑 \u{200c}흑");
+            assert_eq!(parser.next().map(|(_, d)| d), Some(0));
+            assert_eq!(parser.next().map(|(_, d)| d), Some(1));
+
+            let mut parser = Parser::with_ignored("혀\u{200b}엉 흑", &[]);
+            assert_instruction!(parser, make_instruction!('형', 2, 0, _));
+            assert_instruction!(parser, make_instruction!('흑', 1, 0, _));
+        }
+
+        #[test]
+        fn with_counter() {
+            use super::super::FORMAT_CHARS;
+
+            fn filler(c: char) -> bool {
+                "어아으".contains(c)
+            }
+            let source = "혀어일엉. 하아앗 흐읏 흐앙읍";
+            let mut parser = Parser::new(source);
+            assert_instruction!(parser, make_instruction!('형', 4, 1, _));
+            assert_instruction!(parser, make_instruction!('핫', 3, 0, _));
+            assert_instruction!(parser, make_instruction!('흣', 2, 0, _));
+            assert_instruction!(parser, make_instruction!('흡', 3, 0, _));
+            assert_instruction!(parser);
+
+            let mut parser = Parser::with_counter(source, FORMAT_CHARS, filler);
+            assert_instruction!(parser, make_instruction!('형', 3, 1, _));
+            assert_instruction!(parser, make_instruction!('핫', 3, 0, _));
+            assert_instruction!(parser, make_instruction!('흣', 2, 0, _));
+            assert_instruction!(parser, make_instruction!('흡', 2, 0, _));
+            assert_instruction!(parser);
+        }
+
+        #[test]
+        fn spans() {
+            use super::super::super::structure::{LineColumn, Span};
+
+            let at = |line, column| LineColumn { line, column };
+            let source = "형.\n하아앙..♥ 말 💖\n흑";
+            let spans = Parser::new(source)
+                .map(|instr| instr.span().cloned().unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(
+                spans,
+                vec![
+                    Span {
+                        bytes: 0..4,
+                        chars: 0..2,
+                        start: at(1, 1),
+                        end: at(1, 3),
+                    },
+                    // Up to the last token, past the prose
+                    Span {
+                        bytes: 5..28,
+                        chars: 3..13,
+                        start: at(2, 1),
+                        end: at(2, 11),
+                    },
+                    Span {
+                        bytes: 29..32,
+                        chars: 14..15,
+                        start: at(3, 1),
+                        end: at(3, 2),
+                    },
+                ]
+            );
+            assert_eq!(&source[spans[1].bytes.clone()], "하아앙..♥ 말 💖");
+            assert!(make_instruction!('흑', 1, 0, _).span().is_none());
+        }
+
+        #[test]
+        fn remaining_bytes() {
+            let mut parser = Parser::new("형. 항.. 흑");
+            // The first operation is read up front, and each instruction reads the next one
+            assert_eq!(parser.remaining_bytes(), 11);
+            assert_instruction!(parser, make_instruction!('형', 1, 1, _));
+            assert_eq!(parser.remaining_bytes(), 6);
+            assert_instruction!(parser, make_instruction!('항', 1, 2, _));
+            assert_eq!(parser.remaining_bytes(), 0);
+            assert_instruction!(parser, make_instruction!('흑', 1, 0, _));
+            assert_instruction!(parser);
+            assert_eq!(parser.remaining_bytes(), 0);
+        }
+
+        #[test]
+        fn from_tokens() {
+            use super::super::{Event, Token};
+
+            let push = Operation::from_chars('혀', Some('엉'), 2);
+            let dup = Operation::from_chars('흑', None, 1);
+            let events = vec![
+                Event::Token(Token::Heart(0)),
+                Event::Operation(push),
+                Event::Token(Token::Dot),
+                Event::Token(Token::ThreeDots),
+                Event::Token(Token::Heart(1)),
+                Event::Token(Token::Dot),
+                Event::Operation(dup),
+                Event::Operation(push),
+            ];
+            let mut parser = Parser::from_tokens(events);
+            assert_instruction!(parser, make_instruction!('형', 2, 4, 1));
+            assert_instruction!(parser, make_instruction!('흑', 1, 0, _));
+            assert_instruction!(parser, make_instruction!('형', 2, 0, _));
+            assert_instruction!(parser);
+
+            let source = "혀엉...♥!♡? 흐읏. 흑..💖";
+            let instructions = Parser::new(source).collect::<Vec<_>>();
+            let mut events = vec![];
+            for c in source.chars() {
+                if let Some(token) = Token::from_char(c) {
+                    events.push(Event::Token(token));
+                }
+                if c == '엉' {
+                    events.push(Event::Operation(push));
+                }
+                if c == '읏' {
+                    events.push(Event::Operation(Operation::from_chars('흐', Some('읏'), 2)));
+                }
+                if c == '흑' {
+                    events.push(Event::Operation(dup));
+                }
+            }
+            assert_eq!(
+                Parser::from_tokens(events).collect::<Vec<_>>(),
+                instructions
+            );
+        }
+
+        #[test]
+        fn dangling_tokens() {
+            use super::super::Token;
+
+            let mut parser = Parser::new(". ♡ 흐 ! 형... ♥");
+            assert_eq!(
+                parser.dangling(),
+                &[
+                    (Token::Dot, 0..1),
+                    (Token::ReturnHeart, 2..5),
+                    (Token::ExclamationMark, 10..11),
+                ]
+            );
+            assert_instruction!(parser, make_instruction!('형', 1, 3, 0));
+            assert_instruction!(parser);
+
+            let parser = Parser::new("…?");
+            assert_eq!(
+                parser.dangling(),
+                &[(Token::ThreeDots, 0..3), (Token::QuestionMark, 3..4)]
+            );
+
+            let parser = Parser::new("혀엉..");
+            assert!(parser.dangling().is_empty());
+        }
+
         #[test]
         fn triple_dots() {
             // Testcase from https://github.com/xnuk/hyeong-testcases
@@ -431,6 +1326,33 @@ mod tests {
             assert_instruction!(parser);
         }
 
+        #[test]
+        fn mixed_dots() {
+            let cases = [
+                (".…..", 6),
+                ("…", 3),
+                ("⋮.⋯", 7),
+                ("…⋮⋯", 9),
+                ("...…", 6),
+                ("..…..⋮.", 11),
+                // Prose between dots doesn't end the run
+                (". x … y ⋯", 7),
+            ];
+            for &(dots, count) in &cases {
+                let source = format!("형{}", dots);
+                let mut parser = Parser::new(&source);
+                assert_instruction!(parser, make_instruction!('형', 1, count, _));
+                assert_instruction!(parser);
+            }
+
+            // Dots after a heart or a question mark don't count, whichever kind they are
+            let mut parser = Parser::new("형.…♥… 형⋮?.");
+            let heart = HEART_MARKS.iter().position(|c| *c == '♥').unwrap() as u64;
+            assert_instruction!(parser, make_instruction!('형', 1, 4, heart));
+            assert_eq!(parser.next().unwrap().dots(), 3);
+            assert_instruction!(parser);
+        }
+
         #[test]
         fn hearts() {
             let black_heart_suit_idx = HEART_MARKS.iter().position(|c| *c == '♥').unwrap() as u64;
@@ -472,5 +1394,41 @@ mod tests {
             assert_instruction!(parser, make_instruction!('흣', 2, 3, eq[_][eq[ret][_]]));
             assert_instruction!(parser);
         }
+
+        #[test]
+        fn first_heart_in_group() {
+            let black_heart_suit_idx = HEART_MARKS.iter().position(|c| *c == '♥').unwrap() as u64;
+            let sparkling_heart_idx = HEART_MARKS.iter().position(|c| *c == '💖').unwrap() as u64;
+
+            let mut parser = Parser::new("형♥♡");
+            assert_instruction!(parser, make_instruction!('형', 1, 0, black_heart_suit_idx));
+            assert_instruction!(parser);
+
+            let mut parser = Parser::new("형♡♥");
+            assert_instruction!(parser, make_instruction!('형', 1, 0, ret));
+            assert_instruction!(parser);
+
+            let mut parser = Parser::new("형💖♥");
+            assert_instruction!(parser, make_instruction!('형', 1, 0, sparkling_heart_idx));
+            assert_instruction!(parser);
+
+            let mut parser = Parser::new("형♡♡");
+            assert_instruction!(parser, make_instruction!('형', 1, 0, ret));
+            assert_instruction!(parser);
+
+            let mut parser = Parser::new("형♥♡!♡♥");
+            assert_instruction!(
+                parser,
+                make_instruction!('형', 1, 0, eq[black_heart_suit_idx][ret])
+            );
+            assert_instruction!(parser);
+
+            let mut parser = Parser::new("형♡♥?💖♡");
+            assert_instruction!(
+                parser,
+                make_instruction!('형', 1, 0, less[ret][sparkling_heart_idx])
+            );
+            assert_instruction!(parser);
+        }
     }
 }
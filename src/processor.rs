@@ -1,38 +1,433 @@
-use super::stack::{HeartResult, StackManager};
-use super::structure::{Instruction, OperationType};
-use std::collections::HashMap;
+use super::error::Error;
+use super::jump::{JumpStrategy, Labels};
+use super::program::Program;
+use super::rational::HyeongRational;
+use super::stack::{HeartResult, StackManager, StackSnapshot};
+use super::structure::{Instruction, OperationType, Span};
+use std::fmt::{self, Display, Formatter};
 use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+use std::{iter, slice, vec};
 
-pub struct Processor<P, I: Read, O: Write, E: Write> {
+/// The exit code of a program stopped for going over the jump limit. See
+/// `Processor::set_max_jumps`.
+pub const JUMP_LIMIT_EXIT_CODE: isize = -4;
+
+/// The exit code of a program stopped for running out of fuel. See `Processor::set_fuel`.
+pub const FUEL_EXIT_CODE: isize = -7;
+
+/// The exit code of a program stopped for running past its deadline. See
+/// `Processor::set_deadline`.
+pub const TIMEOUT_EXIT_CODE: isize = -8;
+
+/// Instructions executed between looking at the clock while a deadline is set.
+const DEADLINE_CHECK_INTERVAL: u32 = 1024;
+
+/// Noteworthy things happening during execution, reported to the trace hook.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceEvent {
+    /// The instruction at `position` resolved to a return heart, but there was nowhere to
+    /// return to, so execution fell through.
+    ReturnWithoutJump { position: usize },
+}
+
+/// What happened on a single step of execution.
+#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StepOutcome {
+    /// The program is still running.
+    Continue,
+    /// The program has ended with the exit code.
+    Exit(isize),
+}
+
+/// The exit code of a finished run, and whether flushing the output at the end succeeded.
+pub type RunResult = (isize, io::Result<()>);
+
+/// A complete record of a run, for reproducing and comparing executions. See
+/// `Processor::record`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Transcript {
+    /// Positions of the instructions executed, in order.
+    pub positions: Vec<usize>,
+    /// The outcome of the last step. Every step before it continued.
+    pub outcome: StepOutcome,
+    /// The state of the stacks when the program ended.
+    pub stacks: StackSnapshot,
+    /// What was written to stdout and stderr. Output is always valid UTF-8.
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// A report of a run which ended abnormally. See `Processor::run_with_core_dump`.
+#[derive(Debug)]
+pub struct CoreDump {
+    pub exit_code: isize,
+    /// Why flushing the output at the end failed, if it did.
+    pub io_error: Option<io::Error>,
+    /// Position of the instruction which ended the program, if any did.
+    pub position: Option<usize>,
+    /// That instruction in canonical form.
+    pub instruction: Option<String>,
+    /// Where that instruction is in the source, if it was parsed from one.
+    pub span: Option<Span>,
+    /// The state of the stacks when the program ended.
+    pub stacks: StackSnapshot,
+}
+
+fn write_value(f: &mut Formatter, value: &HyeongRational) -> fmt::Result {
+    match value {
+        HyeongRational::Rational(r) => write!(f, "{}", r),
+        HyeongRational::NaN => write!(f, "NaN"),
+    }
+}
+
+/// Summarizes the dump over several lines: the exit code and where the program ended, then the
+/// size and top value of each numbered stack which isn't empty. Empty stacks are skipped even
+/// if a hand-made snapshot lists them.
+impl Display for CoreDump {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "program ended with exit code {}", self.exit_code)?;
+        if let Some(position) = self.position {
+            write!(f, " at instruction {}", position)?;
+        }
+        if let Some(span) = &self.span {
+            write!(
+                f,
+                " (line {}, column {})",
+                span.start.line, span.start.column
+            )?;
+        }
+        if let Some(instruction) = &self.instruction {
+            write!(f, ": {}", instruction)?;
+        }
+        if let Some(e) = &self.io_error {
+            write!(f, "\nflushing output failed: {}", e)?;
+        }
+        write!(f, "\nselected stack: {}", self.stacks.selected)?;
+        for (id, values) in &self.stacks.stacks {
+            if let Some(top) = values.last() {
+                write!(f, "\nstack {}: {} value(s), top ", id, values.len())?;
+                write_value(f, top)?;
+            }
+        }
+        if !self.stacks.input.is_empty() {
+            write!(
+                f,
+                "\ninput: {} value(s) pushed back",
+                self.stacks.input.len()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CoreDump {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.io_error
+            .as_ref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// The state of a paused processor, to resume it later with `Processor::from_snapshot`,
+/// possibly in another process. See `Processor::snapshot`.
+///
+/// IO streams can't be saved, so only values pushed back onto stdin are kept. Settings like
+/// limits and hooks aren't part of the state either.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ProcessorSnapshot {
+    /// The whole program.
+    pub instructions: Vec<Instruction>,
+    /// Position of the instruction to execute next.
+    pub position: usize,
+    pub labels: Labels,
+    pub jump_count: u64,
+    pub stacks: StackSnapshot,
+}
+
+/// Executions of and wall-clock time spent on each operation type.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimingProfile {
+    counts: [u64; 6],
+    nanos: [u64; 6],
+}
+
+impl TimingProfile {
+    pub fn count(&self, op: OperationType) -> u64 {
+        self.counts[op as usize]
+    }
+
+    pub fn time(&self, op: OperationType) -> Duration {
+        Duration::from_nanos(self.nanos[op as usize])
+    }
+
+    pub fn total_time(&self) -> Duration {
+        Duration::from_nanos(self.nanos.iter().sum())
+    }
+
+    fn record(&mut self, op: OperationType, elapsed: Duration) {
+        self.counts[op as usize] += 1;
+        self.nanos[op as usize] += elapsed.as_nanos() as u64;
+    }
+}
+
+pub struct Processor<P, I: Read, O: Write, E: Write, J = Labels> {
     inner: P,
     instructions: Vec<Instruction>,
     position: usize,
     stacks: StackManager<I, O, E>,
-    last_jump: Option<usize>,
-    labels: HashMap<(u64, u64), usize>,
+    jumps: J,
+    trace: Option<Box<dyn FnMut(TraceEvent) + Send>>,
+    timing: Option<TimingProfile>,
+    listing: Option<Box<dyn Write + Send>>,
+    pop_on_return: bool,
+    entropy: Option<Box<dyn FnMut() -> u64 + Send>>,
+    jump_count: u64,
+    max_jumps: Option<u64>,
+    fuel: Option<u64>,
+    deadline: Option<Instant>,
+    deadline_countdown: u32,
+    exit_instruction: Option<usize>,
+    /// The exit code of the limit which stopped the program, if one did, so that lifting it can
+    /// forget where the program stopped.
+    limit_hit: Option<isize>,
 }
 
 impl<P, I: Read, O: Write, E: Write> Processor<P, I, O, E> {
     pub fn with_stack_manager(inner: P, stacks: StackManager<I, O, E>) -> Self {
+        Self::with_jump_strategy(inner, stacks, Labels::new())
+    }
+}
+
+impl<'a, I: Read, O: Write, E: Write>
+    Processor<iter::Cloned<slice::Iter<'a, Instruction>>, I, O, E>
+{
+    /// Creates a processor running `program` with `stacks`. The program is only borrowed, so
+    /// it can be parsed once and run any number of times with different IO.
+    pub fn from_program(program: &'a Program, stacks: StackManager<I, O, E>) -> Self {
+        Self::with_stack_manager(program.instructions().iter().cloned(), stacks)
+    }
+}
+
+impl<I: Read, O: Write, E: Write> Processor<vec::IntoIter<Instruction>, I, O, E> {
+    /// Resumes the processor saved in `snapshot`, doing IO with `stacks` from now on. The
+    /// numbered stacks and the selected stack of `stacks` are replaced with those saved.
+    pub fn from_snapshot(snapshot: ProcessorSnapshot, mut stacks: StackManager<I, O, E>) -> Self {
+        stacks.restore(&snapshot.stacks);
+        let mut processor =
+            Self::with_jump_strategy(Vec::new().into_iter(), stacks, snapshot.labels);
+        processor.instructions = snapshot.instructions;
+        processor.position = snapshot.position;
+        processor.jump_count = snapshot.jump_count;
+        processor
+    }
+}
+
+impl<P, I: Read, O: Write, E: Write, J: JumpStrategy> Processor<P, I, O, E, J> {
+    /// Creates a processor which consults `jumps` instead of the specified label semantics.
+    pub fn with_jump_strategy(inner: P, stacks: StackManager<I, O, E>, jumps: J) -> Self {
         Processor {
             inner,
             instructions: vec![],
             position: 0,
             stacks,
-            last_jump: None,
-            labels: HashMap::new(),
+            jumps,
+            trace: None,
+            timing: None,
+            listing: None,
+            pop_on_return: false,
+            entropy: None,
+            jump_count: 0,
+            max_jumps: None,
+            fuel: None,
+            deadline: None,
+            deadline_countdown: 0,
+            exit_instruction: None,
+            limit_hit: None,
+        }
+    }
+
+    /// Starts measuring the time spent on each operation type.
+    ///
+    /// This reads the clock twice per instruction, which is a noticeable overhead for programs
+    /// made of cheap operations.
+    pub fn enable_timing(&mut self) {
+        self.timing.get_or_insert_with(TimingProfile::default);
+    }
+
+    /// The time measured so far, if timing is enabled.
+    pub fn timing_profile(&self) -> Option<&TimingProfile> {
+        self.timing.as_ref()
+    }
+
+    pub fn stack_manager(&self) -> &StackManager<I, O, E> {
+        &self.stacks
+    }
+
+    /// Position of the instruction to execute next. At the end of the program this is one past
+    /// the last instruction until the next step wraps around.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// The instructions loaded so far. Instructions are loaded from the iterator as execution
+    /// first reaches them.
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    /// The jump strategy, e.g. `Labels` with the label table and the last jump.
+    pub fn jump_strategy(&self) -> &J {
+        &self.jumps
+    }
+
+    pub fn stack_manager_mut(&mut self) -> &mut StackManager<I, O, E> {
+        &mut self.stacks
+    }
+
+    /// Sets a hook called with each `TraceEvent`. Tracing doesn't affect execution.
+    pub fn set_trace<F: FnMut(TraceEvent) + Send + 'static>(&mut self, hook: F) {
+        self.trace = Some(Box::new(hook));
+    }
+
+    /// Writes each instruction to `writer` before executing it, as a line like `[42] 항...♥!`
+    /// with its position. Like tracing, this doesn't affect execution; write errors are ignored.
+    pub fn enable_listing<W: Write + Send + 'static>(&mut self, writer: W) {
+        self.listing = Some(Box::new(writer));
+    }
+
+    /// Makes an instruction resolving to the return heart pop a value off the selected stack
+    /// before returning, as if returning from a call with a result.
+    ///
+    /// This is a dialect: programs relying on it don't run the same on other interpreters, and
+    /// specified programs may not run correctly with it.
+    pub fn set_pop_on_return(&mut self, pop_on_return: bool) {
+        self.pop_on_return = pop_on_return;
+    }
+
+    /// Lets the program jump at most `max` times; the jump after that ends it with
+    /// `JUMP_LIMIT_EXIT_CODE`. Only hearts which move execution elsewhere count, not labels
+    /// resolving to themselves nor returns. `None` removes the limit.
+    ///
+    /// A processor over the limit keeps ending with `JUMP_LIMIT_EXIT_CODE` without executing
+    /// anything. Raising the limit resumes it at the target of the jump.
+    pub fn set_max_jumps(&mut self, max: Option<u64>) {
+        self.max_jumps = max;
+        self.lift_limit(JUMP_LIMIT_EXIT_CODE);
+    }
+
+    /// Number of jumps taken so far, including one which went over the limit.
+    pub fn jump_count(&self) -> u64 {
+        self.jump_count
+    }
+
+    /// Lets the program execute at most `fuel` more instructions; the instruction after that
+    /// isn't executed, but ends the program with `FUEL_EXIT_CODE`. Unlike the jump limit, this
+    /// bounds any program, however it loops. `None` removes the limit.
+    ///
+    /// A processor out of fuel keeps ending with `FUEL_EXIT_CODE` until it is given more, and
+    /// then continues where it stopped.
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.fuel = fuel;
+        self.lift_limit(FUEL_EXIT_CODE);
+    }
+
+    /// The fuel left, if it is limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    /// Ends the program with `TIMEOUT_EXIT_CODE` once `deadline` has passed. The clock is read
+    /// only every so many instructions, so the program may overrun the deadline slightly; an
+    /// instruction blocked on reading input isn't interrupted at all. `None` removes the
+    /// deadline.
+    pub fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+        self.deadline_countdown = 0;
+        self.lift_limit(TIMEOUT_EXIT_CODE);
+    }
+
+    /// Position of the instruction which ended the program, once it has ended. `None` for an
+    /// empty program, which ends without executing anything.
+    pub fn exit_instruction(&self) -> Option<usize> {
+        self.exit_instruction
+    }
+
+    /// Sets a source of randomness for dialects which need it. No specified operation consults
+    /// it, so without one execution stays deterministic.
+    pub fn set_entropy_source<F: FnMut() -> u64 + Send + 'static>(&mut self, source: F) {
+        self.entropy = Some(Box::new(source));
+    }
+
+    /// Draws a value from the entropy source, if there is one.
+    pub fn next_entropy(&mut self) -> Option<u64> {
+        self.entropy.as_mut().map(|source| source())
+    }
+
+    /// Stops the program for going over the limit with `code`, at the instruction at `position`.
+    fn hit_limit(&mut self, code: isize, position: usize) -> Option<isize> {
+        self.exit_instruction.get_or_insert(position);
+        self.limit_hit.get_or_insert(code);
+        Some(code)
+    }
+
+    /// Forgets where the program stopped if the limit with `code` stopped it, as the limit has
+    /// changed and the program may run on.
+    fn lift_limit(&mut self, code: isize) {
+        if self.limit_hit == Some(code) {
+            self.limit_hit = None;
+            self.exit_instruction = None;
+        }
+    }
+
+    fn emit(&mut self, event: TraceEvent) {
+        if let Some(trace) = &mut self.trace {
+            trace(event);
         }
     }
 }
 
-impl<P, I: Read, O: Write, E: Write> Drop for Processor<P, I, O, E> {
+impl<P, I, O, E> Processor<P, I, O, E, Labels>
+where
+    P: Iterator<Item = Instruction>,
+    I: Read,
+    O: Write,
+    E: Write,
+{
+    /// Saves the state of the processor, to resume it with `from_snapshot`. This reads the rest
+    /// of the program first, so it doesn't return for endless instruction iterators.
+    pub fn snapshot(&mut self) -> ProcessorSnapshot {
+        self.instructions.extend(self.inner.by_ref());
+        ProcessorSnapshot {
+            instructions: self.instructions.clone(),
+            position: self.position,
+            labels: self.jumps.clone(),
+            jump_count: self.jump_count,
+            stacks: self.stacks.snapshot(),
+        }
+    }
+}
+
+impl<P, I: Read, O: Write, E: Write, J> Drop for Processor<P, I, O, E, J> {
     fn drop(&mut self) {
-        self.stacks.flush().unwrap();
+        // Nothing can be done about an error here; running to the end or flushing the stack
+        // manager reports it
+        let _ = self.stacks.flush();
     }
 }
 
-impl<P: Iterator<Item = Instruction>, I: Read, O: Write, E: Write> Processor<P, I, O, E> {
-    pub fn run(mut self) -> (isize, io::Result<()>) {
+impl<P, I, O, E, J> Processor<P, I, O, E, J>
+where
+    P: Iterator<Item = Instruction>,
+    I: Read,
+    O: Write,
+    E: Write,
+    J: JumpStrategy,
+{
+    pub fn run(mut self) -> RunResult {
         loop {
             if let Some(x) = self.advance() {
                 return (x, self.stacks.flush());
@@ -40,15 +435,68 @@ impl<P: Iterator<Item = Instruction>, I: Read, O: Write, E: Write> Processor<P,
         }
     }
 
-    pub fn advance(&mut self) -> Option<isize> {
+    /// Loads the instruction to execute next if needed, wrapping around at the end of the
+    /// program. Returns its position, or `None` if the program is empty.
+    fn fetch(&mut self) -> Option<usize> {
         if self.instructions.len() <= self.position {
             match self.inner.next() {
                 None => self.position = 0,
                 Some(instr) => self.instructions.push(instr),
             }
         }
-        let instr = &self.instructions[self.position];
+        if self.instructions.is_empty() {
+            None
+        } else {
+            Some(self.position)
+        }
+    }
 
+    /// The instruction to execute next and its position, or `None` if the program is empty.
+    /// This loads the instruction if it hasn't been reached yet, and resolves wrapping around at
+    /// the end of the program, without executing anything.
+    pub fn next_instruction(&mut self) -> Option<(usize, &Instruction)> {
+        let position = self.fetch()?;
+        Some((position, &self.instructions[position]))
+    }
+
+    /// Executes one instruction, returning the exit code if the program has ended.
+    ///
+    /// Reaching the end of the program is not termination; execution restarts from the first
+    /// instruction, as the language specifies. A program ends only by selecting stack 1 or 2
+    /// (or by being empty).
+    pub fn advance(&mut self) -> Option<isize> {
+        if self.fetch().is_none() {
+            // Empty program; nothing to run
+            return Some(0);
+        }
+        let position = self.position;
+        if self.max_jumps.is_some_and(|max| self.jump_count > max) {
+            // The limit was already hit; stay stopped instead of executing again
+            return Some(JUMP_LIMIT_EXIT_CODE);
+        }
+        // Checked before charging fuel, so that only executed instructions use it up
+        if let Some(deadline) = self.deadline {
+            if self.deadline_countdown == 0 {
+                if Instant::now() >= deadline {
+                    return self.hit_limit(TIMEOUT_EXIT_CODE, position);
+                }
+                self.deadline_countdown = DEADLINE_CHECK_INTERVAL;
+            }
+            self.deadline_countdown -= 1;
+        }
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return self.hit_limit(FUEL_EXIT_CODE, position);
+            }
+            *fuel -= 1;
+        }
+        let instr = &self.instructions[position];
+        if let Some(listing) = &mut self.listing {
+            let source = instr.to_source().unwrap_or_else(|| format!("{:?}", instr));
+            let _ = writeln!(listing, "[{}] {}", self.position, source);
+        }
+
+        let started = self.timing.as_ref().map(|_| Instant::now());
         match instr.operation_type() {
             OperationType::Push => {
                 self.stacks.push(instr.hangul_count(), instr.dots());
@@ -69,42 +517,209 @@ impl<P: Iterator<Item = Instruction>, I: Read, O: Write, E: Write> Processor<P,
                 self.stacks.dup(instr.hangul_count(), instr.dots());
             }
         }
+        if let (Some(timing), Some(started)) = (&mut self.timing, started) {
+            timing.record(instr.operation_type(), started.elapsed());
+        }
 
         let param = instr.hangul_times_dots();
         let heart = instr.heart_tree();
         let result = self.stacks.process_hearts(heart, param);
-        match result {
+        let next = match result {
             HeartResult::Heart(id) => {
-                let label = (param, id);
-                let next = *(self.labels.entry(label).or_insert(self.position));
-                if next != self.position {
-                    self.last_jump = Some(self.position);
-                    self.position = next;
-                } else {
-                    self.position += 1;
+                let next = self.jumps.heart(param, id, self.position);
+                if next.is_some() {
+                    self.jump_count += 1;
+                    if self.max_jumps.is_some_and(|max| self.jump_count > max) {
+                        self.position = next.unwrap_or(position);
+                        return self.hit_limit(JUMP_LIMIT_EXIT_CODE, position);
+                    }
                 }
+                next
             }
             HeartResult::Return => {
-                if let Some(next) = self.last_jump {
-                    self.position = next;
-                } else {
-                    self.position += 1;
+                if self.pop_on_return {
+                    self.stacks.pop();
+                }
+                let next = self.jumps.ret(self.position);
+                if next.is_none() {
+                    let position = self.position;
+                    self.emit(TraceEvent::ReturnWithoutJump { position });
                 }
+                next
+            }
+            HeartResult::Nil => None,
+        };
+        self.position = next.unwrap_or(self.position + 1);
+
+        let code = self.stacks.exit_code();
+        if code.is_some() {
+            self.exit_instruction.get_or_insert(position);
+        }
+        code
+    }
+
+    /// Executes instructions until `done` returns true for the stacks, which is checked before
+    /// each instruction. Returns the exit code if the program ends first.
+    pub fn run_until<F>(&mut self, mut done: F) -> Option<isize>
+    where
+        F: FnMut(&StackManager<I, O, E>) -> bool,
+    {
+        while !done(&self.stacks) {
+            if let Some(code) = self.advance() {
+                return Some(code);
+            }
+        }
+        None
+    }
+
+    /// Executes instructions until the numbered stack `id` is empty. Returns the exit code if
+    /// the program ends first.
+    ///
+    /// IO stacks are never considered empty, so for those this runs until the program ends. In
+    /// particular, stdin can't be known to be empty before input runs out.
+    pub fn run_until_stack_empty(&mut self, id: u64) -> Option<isize> {
+        self.run_until(|stacks| stacks.stack_len(id) == Some(0))
+    }
+
+    /// Runs the program to the end like `run`, recording a `Transcript` of it on the way.
+    ///
+    /// The transcript holds every position executed, so this takes memory proportional to the
+    /// length of the run.
+    pub fn record(mut self) -> (RunResult, Transcript) {
+        self.stacks.enable_capture();
+        let mut positions = vec![];
+        let code = loop {
+            if let Some(position) = self.fetch() {
+                positions.push(position);
+            }
+            if let Some(code) = self.advance() {
+                break code;
             }
-            _ => {
-                self.position += 1;
+        };
+        let captured = |captured: Option<&[u8]>| {
+            String::from_utf8_lossy(captured.unwrap_or_default()).into_owned()
+        };
+        let transcript = Transcript {
+            positions,
+            outcome: StepOutcome::Exit(code),
+            stacks: self.stacks.snapshot(),
+            stdout: captured(self.stacks.stdout().captured()),
+            stderr: captured(self.stacks.stderr().captured()),
+        };
+        ((code, self.stacks.flush()), transcript)
+    }
+
+    /// Runs the program to the end like `run`, returning the exit code, or the error writing
+    /// the output failed with. Failing to write ends the program early; see
+    /// `WRITE_ERROR_EXIT_CODE`.
+    ///
+    /// Computing NaN while that is fatal gives `Error::UnexpectedNan` with the position of the
+    /// instruction which did, instead of `NAN_EXIT_CODE`.
+    pub fn try_run(mut self) -> Result<isize, Error> {
+        let code = loop {
+            if let Some(code) = self.advance() {
+                break code;
+            }
+        };
+        self.stacks.flush()?;
+        match self.exit_instruction {
+            Some(position) if self.stacks.stopped_on_nan() => {
+                Err(Error::UnexpectedNan { position })
+            }
+            _ => Ok(code),
+        }
+    }
+
+    /// Runs the program like `run`, executing at most `fuel` instructions. A program which
+    /// doesn't end by then ends with `FUEL_EXIT_CODE`, so this returns even for endless loops.
+    pub fn run_with_fuel(mut self, fuel: u64) -> RunResult {
+        self.set_fuel(Some(fuel));
+        self.run()
+    }
+
+    /// Runs the program like `run`, for at most about `timeout`. A program which doesn't end by
+    /// then ends with `TIMEOUT_EXIT_CODE`, with its output flushed. See `set_deadline`.
+    pub fn run_with_timeout(mut self, timeout: Duration) -> RunResult {
+        // A timeout too long to represent is as good as none
+        self.set_deadline(Instant::now().checked_add(timeout));
+        self.run()
+    }
+
+    /// Runs the program to the end like `run`, but reports an abnormal end with a `CoreDump`: a
+    /// negative exit code, as the limits and fatal conditions give, or failing to flush the
+    /// output.
+    pub fn run_with_core_dump(mut self) -> Result<isize, Box<CoreDump>> {
+        let code = loop {
+            if let Some(code) = self.advance() {
+                break code;
             }
+        };
+        let flushed = self.stacks.flush();
+        if code >= 0 && flushed.is_ok() {
+            return Ok(code);
         }
+        let instr = self
+            .exit_instruction
+            .map(|position| &self.instructions[position]);
+        Err(Box::new(CoreDump {
+            exit_code: code,
+            io_error: flushed.err(),
+            position: self.exit_instruction,
+            instruction: instr
+                .map(|instr| instr.to_source().unwrap_or_else(|| format!("{:?}", instr))),
+            span: instr.and_then(|instr| instr.span().cloned()),
+            stacks: self.stacks.snapshot(),
+        }))
+    }
 
-        self.stacks.exit_code()
+    /// Executes one instruction, like `advance`.
+    pub fn step(&mut self) -> StepOutcome {
+        match self.advance() {
+            Some(code) => StepOutcome::Exit(code),
+            None => StepOutcome::Continue,
+        }
+    }
+
+    /// Executes up to `n` instructions, stopping early if the program ends. Also returns how
+    /// many instructions were executed, counting the one which ended the program.
+    pub fn step_n(&mut self, n: u64) -> (StepOutcome, u64) {
+        for executed in 1..=n {
+            if let Some(code) = self.advance() {
+                return (StepOutcome::Exit(code), executed);
+            }
+        }
+        (StepOutcome::Continue, n)
+    }
+
+    /// Like `advance`, but takes and gives back the processor, for drivers which thread it by
+    /// value.
+    ///
+    /// The processor is moved rather than dropped, so its stacks are flushed only once, when it
+    /// is finally dropped or run to the end.
+    pub fn step_owned(mut self) -> (Self, StepOutcome) {
+        let outcome = self.step();
+        (self, outcome)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::error::Error;
+    use super::super::jump::{JumpStrategy, Labels};
     use super::super::parser::Parser;
-    use super::super::stack::{HyeongReadStack, HyeongWriteStack, StackManager};
-    use super::Processor;
+    use super::super::program::Program;
+    use super::super::stack::{
+        CallbackWriter, HyeongReadStack, HyeongWriteStack, SharedWriter, StackManager,
+        StringWriter, DEPTH_LIMIT_EXIT_CODE, EOF_LIMIT_EXIT_CODE, NAN_EXIT_CODE,
+        OUTPUT_LIMIT_EXIT_CODE, WRITE_ERROR_EXIT_CODE,
+    };
+    use super::super::structure::OperationType;
+    use super::{
+        Processor, StepOutcome, TraceEvent, FUEL_EXIT_CODE, JUMP_LIMIT_EXIT_CODE, TIMEOUT_EXIT_CODE,
+    };
+    use std::io;
+    use std::num::NonZeroUsize;
+    use std::time::{Duration, Instant};
 
     macro_rules! test_path {
         ($name:expr, $ext:expr) => {
@@ -181,4 +796,656 @@ mod tests {
     fn stderr() {
         test!("stderr", error, exitcode);
     }
+
+    #[test]
+    fn custom_jump_strategy() {
+        struct Counting(Labels, usize);
+        impl JumpStrategy for Counting {
+            fn heart(&mut self, param: u64, id: u64, position: usize) -> Option<usize> {
+                self.1 += 1;
+                self.0.heart(param, id, position)
+            }
+            fn ret(&mut self, position: usize) -> Option<usize> {
+                self.1 += 1;
+                self.0.ret(position)
+            }
+        }
+
+        let mut output = vec![];
+        let mut jumps = Counting(Labels::new(), 0);
+        let (exit_code, err) = {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(&mut output);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let parser = Parser::new(include_str!(test_path!("fibonacci", "hyeong")));
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let processor = Processor::with_jump_strategy(parser, stacks, &mut jumps);
+            processor.run()
+        };
+        err.unwrap();
+        assert_eq!(exit_code, 0);
+        assert_eq!(
+            &output[..],
+            include_bytes!(test_path!("fibonacci", "stdout"))
+        );
+        assert!(jumps.1 > 0);
+    }
+
+    #[test]
+    fn return_without_jump() {
+        use std::sync::{Arc, Mutex};
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let parser = Parser::new("형. 형.♥ 형♡ 형.♥");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        let hook_events = events.clone();
+        processor.set_trace(move |event| hook_events.lock().unwrap().push(event));
+        // The second return heart follows the jump made by the last instruction
+        for _ in 0..6 {
+            assert_eq!(processor.advance(), None);
+        }
+        assert_eq!(
+            &events.lock().unwrap()[..],
+            &[TraceEvent::ReturnWithoutJump { position: 2 }]
+        );
+    }
+
+    #[test]
+    fn exit_code_set_externally() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let parser = Parser::new("형. 항.");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        assert_eq!(processor.advance(), None);
+        processor.stack_manager_mut().set_exit_code(7);
+        let (exit_code, err) = processor.run();
+        err.unwrap();
+        assert_eq!(exit_code, 7);
+    }
+
+    #[test]
+    fn timing_profile() {
+        use super::super::structure::OperationType;
+
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let parser = Parser::new("형. 형. 항. 흡");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        assert!(processor.timing_profile().is_none());
+        processor.enable_timing();
+        for _ in 0..5 {
+            assert_eq!(processor.advance(), None);
+        }
+        let profile = processor.timing_profile().unwrap();
+        assert_eq!(profile.count(OperationType::Push), 3);
+        assert_eq!(profile.count(OperationType::Add), 1);
+        assert_eq!(profile.count(OperationType::Reciprocate), 1);
+        assert_eq!(profile.count(OperationType::Duplicate), 0);
+        assert_eq!(profile.time(OperationType::Duplicate), Duration::ZERO);
+        assert!(profile.total_time() >= profile.time(OperationType::Push));
+    }
+
+    #[test]
+    fn wraps_around_at_end() {
+        // The first pass selects stdout without exiting; the second pass exits at `흑`.
+        let mut output = vec![];
+        let (exit_code, err) = {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(&mut output);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let parser = Parser::new("흑. 혀엉....");
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let processor = Processor::with_stack_manager(parser, stacks);
+            processor.run()
+        };
+        err.unwrap();
+        assert_eq!(exit_code, 0);
+        assert_eq!(&output[..], "너무 커엇...\u{8}".as_bytes());
+    }
+
+    #[test]
+    fn output_limit() {
+        let mut output = vec![];
+        let (exit_code, err) = {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::with_limit(&mut output, 100);
+            let stderr = HyeongWriteStack::new(vec![]);
+            // Prints forever: the second push keeps jumping back to the first
+            let parser = Parser::new("흑. 형.♥ 형.♥");
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let processor = Processor::with_stack_manager(parser, stacks);
+            processor.run()
+        };
+        err.unwrap();
+        assert_eq!(exit_code, OUTPUT_LIMIT_EXIT_CODE);
+        assert_eq!(output.len(), 100);
+        assert!(output.starts_with("너무 커엇...\u{1}".as_bytes()));
+    }
+
+    #[test]
+    fn listing() {
+        use std::sync::{Arc, Mutex};
+
+        let listing = Arc::new(Mutex::new(vec![]));
+        let (exit_code, err) = {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(vec![]);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let parser = Parser::new("혀어엉... 흑.. 하앙.♥");
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let mut processor = Processor::with_stack_manager(parser, stacks);
+            let hook_listing = Arc::clone(&listing);
+            processor.enable_listing(CallbackWriter::new(move |buf: &[u8]| {
+                hook_listing.lock().unwrap().extend_from_slice(buf)
+            }));
+            processor.run()
+        };
+        err.unwrap();
+        assert_eq!(exit_code, 1);
+        let listing = listing.lock().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&listing).unwrap(),
+            "[0] 혀어엉...\n[1] 흑..\n[2] 하앙.♥\n",
+        );
+    }
+
+    #[test]
+    fn pop_on_return() {
+        for &pop_on_return in &[false, true] {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(vec![]);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let parser = Parser::new("형. 형.. 형...♡");
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let mut processor = Processor::with_stack_manager(parser, stacks);
+            processor.set_pop_on_return(pop_on_return);
+            for _ in 0..3 {
+                assert_eq!(processor.advance(), None);
+            }
+            let expected = if pop_on_return { 2 } else { 3 };
+            assert_eq!(
+                processor.stack_manager().snapshot().stacks[&3].len(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn run_until_stack_empty() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // Pushes four values onto stack 3, then adds them up two at a time, pushing each sum
+        // onto stack 4
+        let parser = Parser::new("형. 형. 형. 형. 하앙.... 하앙.... 흑.");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        assert_eq!(
+            processor.run_until(|stacks| stacks.stack_len(3) == Some(4)),
+            None
+        );
+        assert_eq!(processor.stack_manager().stack_len(4), Some(0));
+        // Stack 3 drains over two additions, and stops at the first point it is empty
+        assert_eq!(processor.run_until_stack_empty(3), None);
+        assert_eq!(processor.stack_manager().stack_len(3), Some(0));
+        assert_eq!(processor.stack_manager().stack_len(4), Some(2));
+        assert_eq!(processor.position(), 6);
+        assert_eq!(processor.run_until_stack_empty(1), Some(0));
+    }
+
+    #[test]
+    fn eof_limit() {
+        let mut stdin = HyeongReadStack::new(&b"ab"[..]);
+        stdin.set_eof_limit(NonZeroUsize::new(3));
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // Reads a character on every iteration, forever
+        let parser = Parser::new("흑 하앙...");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let processor = Processor::with_stack_manager(parser, stacks);
+        let (exit_code, err) = processor.run();
+        err.unwrap();
+        assert_eq!(exit_code, EOF_LIMIT_EXIT_CODE);
+    }
+
+    #[test]
+    fn record() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let parser = Parser::new("형. 형.. 흑. 형.. 항.");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let processor = Processor::with_stack_manager(parser, stacks);
+        let ((exit_code, err), transcript) = processor.record();
+        err.unwrap();
+        assert_eq!(exit_code, 0);
+        assert_eq!(transcript.positions, vec![0, 1, 2, 3, 4]);
+        assert_eq!(transcript.outcome, StepOutcome::Exit(0));
+        assert_eq!(transcript.stacks.selected, 1);
+        assert_eq!(transcript.stacks.stacks[&3].len(), 2);
+        assert_eq!(transcript.stdout, "\u{2}\u{2}");
+        assert!(transcript.stderr.is_empty());
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&transcript).unwrap();
+            assert_eq!(
+                serde_json::from_str::<super::Transcript>(&json).unwrap(),
+                transcript
+            );
+        }
+    }
+
+    #[test]
+    fn snapshot_resume() {
+        let source = include_str!("../snippets/hello-world.hyeong");
+        let expected = include_bytes!("../snippets/hello-world.stdout");
+        let mut output = vec![];
+        let snapshot = {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(&mut output);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let mut processor = Processor::with_stack_manager(Parser::new(source), stacks);
+            assert_eq!(processor.step_n(20), (StepOutcome::Continue, 20));
+            processor.snapshot()
+        };
+        #[cfg(feature = "serde")]
+        let snapshot = {
+            let json = serde_json::to_string(&snapshot).unwrap();
+            let restored = serde_json::from_str::<super::ProcessorSnapshot>(&json).unwrap();
+            assert_eq!(restored, snapshot);
+            restored
+        };
+        assert!(output.len() < expected.len());
+
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(&mut output);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let (exit_code, err) = Processor::from_snapshot(snapshot, stacks).run();
+        err.unwrap();
+        assert_eq!(exit_code, include!("../snippets/hello-world.exitcode"));
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn core_dump() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // The reciprocal of zero replaces it on stack 3, and goes onto stdin
+        let parser = Parser::new("형.. 형 흡 항.");
+        let mut stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        stacks.set_fatal_nan(true);
+        let mut dump = Processor::with_stack_manager(parser, stacks)
+            .run_with_core_dump()
+            .unwrap_err();
+        assert_eq!(dump.exit_code, NAN_EXIT_CODE);
+        assert_eq!(dump.position, Some(2));
+        assert_eq!(dump.instruction.as_deref(), Some("흡"));
+        assert_eq!(
+            dump.to_string(),
+            "program ended with exit code -6 at instruction 2 (line 1, column 7): 흡\n\
+             selected stack: 3\n\
+             stack 3: 2 value(s), top NaN\n\
+             input: 1 value(s) pushed back"
+        );
+
+        // Public fields can hold an empty stack, which has no top to show
+        dump.stacks.stacks.insert(5, vec![]);
+        assert!(!dump.to_string().contains("stack 5"));
+
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let parser = Parser::new(include_str!("../snippets/hello-world.hyeong"));
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let processor = Processor::with_stack_manager(parser, stacks);
+        assert_eq!(
+            processor.run_with_core_dump().unwrap(),
+            include!("../snippets/hello-world.exitcode")
+        );
+    }
+
+    #[test]
+    fn from_program() {
+        // Echoes a character of input, ending with stderr selected
+        let program = Program::parse("흑 항.. 항. 흑..").unwrap();
+        for input in ["A", "가"] {
+            let mut output = vec![];
+            let (exit_code, err) = {
+                let stdin = HyeongReadStack::new(input.as_bytes());
+                let stdout = HyeongWriteStack::new(&mut output);
+                let stderr = HyeongWriteStack::new(vec![]);
+                let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+                Processor::from_program(&program, stacks).run()
+            };
+            err.unwrap();
+            assert_eq!(exit_code, 1);
+            assert_eq!(output, input.as_bytes());
+        }
+    }
+
+    #[test]
+    fn step_inspection() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // Labels the first instruction, then jumps back to it from the third
+        let parser = Parser::new("형.♥ 흑.... 형.♥");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        assert_eq!(processor.position(), 0);
+        assert!(processor.instructions().is_empty());
+        assert_eq!(processor.step(), StepOutcome::Continue);
+        assert_eq!(processor.jump_strategy().label(1, 0), Some(0));
+        assert_eq!(processor.step(), StepOutcome::Continue);
+        assert_eq!(processor.stack_manager().selected(), 4);
+        assert_eq!(processor.position(), 2);
+        assert_eq!(processor.step(), StepOutcome::Continue);
+        assert_eq!(processor.position(), 0);
+        assert_eq!(processor.instructions().len(), 3);
+        let (position, instr) = processor.next_instruction().unwrap();
+        assert_eq!((position, instr.operation_type()), (0, OperationType::Push));
+        assert_eq!(processor.jump_strategy().last_jump(), Some(2));
+        assert_eq!(
+            processor.jump_strategy().iter().collect::<Vec<_>>(),
+            vec![(1, 0, 0)]
+        );
+    }
+
+    #[test]
+    fn max_jumps() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // The second instruction jumps back to the first, forever
+        let parser = Parser::new("형.♥ 형.♥");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        processor.set_max_jumps(Some(5));
+        assert_eq!(
+            processor.step_n(100),
+            (StepOutcome::Exit(JUMP_LIMIT_EXIT_CODE), 12)
+        );
+        assert_eq!(processor.jump_count(), 6);
+        assert_eq!(processor.stack_manager().stack_len(3), Some(12));
+        assert_eq!(processor.exit_instruction(), Some(1));
+
+        // The limit stays hit, without executing the instruction again
+        for _ in 0..2 {
+            assert_eq!(processor.step(), StepOutcome::Exit(JUMP_LIMIT_EXIT_CODE));
+        }
+        assert_eq!(processor.jump_count(), 6);
+        assert_eq!(processor.stack_manager().stack_len(3), Some(12));
+
+        // Raising it resumes after the jump
+        processor.set_max_jumps(Some(6));
+        assert_eq!(processor.position(), 0);
+        assert_eq!(processor.exit_instruction(), None);
+        assert_eq!(
+            processor.step_n(2),
+            (StepOutcome::Exit(JUMP_LIMIT_EXIT_CODE), 2)
+        );
+        assert_eq!(processor.stack_manager().stack_len(3), Some(14));
+        assert_eq!(processor.exit_instruction(), Some(1));
+    }
+
+    #[test]
+    fn fuel() {
+        let stacks = || {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(vec![]);
+            let stderr = HyeongWriteStack::new(vec![]);
+            StackManager::from_stacks(stdin, stdout, stderr)
+        };
+        // Loops forever without jumping
+        let mut processor = Processor::with_stack_manager(Parser::new("형. 형."), stacks());
+        processor.set_fuel(Some(5));
+        assert_eq!(
+            processor.step_n(100),
+            (StepOutcome::Exit(FUEL_EXIT_CODE), 6)
+        );
+        assert_eq!(processor.fuel(), Some(0));
+        assert_eq!(processor.stack_manager().stack_len(3), Some(5));
+        assert_eq!(processor.exit_instruction(), Some(1));
+        assert_eq!(processor.step(), StepOutcome::Exit(FUEL_EXIT_CODE));
+
+        // Refuelling resumes the program
+        processor.set_fuel(Some(2));
+        assert_eq!(processor.exit_instruction(), None);
+        assert_eq!(processor.step_n(2), (StepOutcome::Continue, 2));
+        assert_eq!(processor.stack_manager().stack_len(3), Some(7));
+
+        // Where the program really ends is reported after running out once: the first non-push
+        // after selecting stderr, once it wraps around
+        let source = "형. 형. 흑 항.. 항. 흑..";
+        let mut processor = Processor::with_stack_manager(Parser::new(source), stacks());
+        processor.set_fuel(Some(1));
+        assert_eq!(processor.step_n(10), (StepOutcome::Exit(FUEL_EXIT_CODE), 2));
+        assert_eq!(processor.exit_instruction(), Some(1));
+        processor.set_fuel(None);
+        assert_eq!(processor.step_n(10), (StepOutcome::Exit(1), 8));
+        assert_eq!(processor.exit_instruction(), Some(2));
+
+        let processor = Processor::with_stack_manager(Parser::new("형.♥ 형.♥"), stacks());
+        let (code, result) = processor.run_with_fuel(1000);
+        assert_eq!(code, FUEL_EXIT_CODE);
+        assert!(result.is_ok());
+
+        // Programs ending in time aren't affected
+        let processor = Processor::with_stack_manager(Parser::new("흑 항.. 항. 흑.."), stacks());
+        assert_eq!(processor.run_with_fuel(10).0, 1);
+    }
+
+    #[test]
+    fn try_run() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(StringWriter::new());
+        let stderr = HyeongWriteStack::new(vec![]);
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let processor = Processor::with_stack_manager(Parser::new("형 형... 흑."), stacks);
+        assert_eq!(processor.try_run().unwrap(), 0);
+
+        // Output going nowhere, like a closed pipe, ends the program with an error rather than
+        // a panic
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(&mut [][..]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        // Prints forever if writing succeeds
+        let source = "형 형... 흑. 형.♥ 형.♥";
+        let mut processor = Processor::with_stack_manager(Parser::new(source), stacks);
+        assert_eq!(
+            processor.step_n(100),
+            (StepOutcome::Exit(WRITE_ERROR_EXIT_CODE), 3)
+        );
+        match processor.try_run() {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::WriteZero),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // Fatal NaN is an error, not an exit code a program could give
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        let mut stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        stacks.set_fatal_nan(true);
+        let processor = Processor::with_stack_manager(Parser::new("형.. 형 흡 항."), stacks);
+        match processor.try_run() {
+            Err(Error::UnexpectedNan { position }) => assert_eq!(position, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let stacks = || {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(SharedWriter::new(vec![]));
+            let stderr = HyeongWriteStack::new(vec![]);
+            StackManager::from_stacks(stdin, stdout, stderr)
+        };
+        // Prints a character, then loops forever printing more
+        let source = "형 형... 흑. 형.♥ 형.♥";
+        let looping = stacks();
+        let output = looping.stdout().get_ref().clone();
+        let processor = Processor::with_stack_manager(Parser::new(source), looping);
+        let (code, result) = processor.run_with_timeout(Duration::from_millis(50));
+        assert_eq!(code, TIMEOUT_EXIT_CODE);
+        assert!(result.is_ok());
+        let output = output.try_into_inner().unwrap();
+        assert_eq!(output[..2], b"\x03\x01"[..]);
+
+        // A deadline which has passed stops the program before anything runs, and keeps it
+        // stopped
+        let mut processor = Processor::with_stack_manager(Parser::new(source), stacks());
+        processor.set_deadline(Some(Instant::now()));
+        processor.set_fuel(Some(5));
+        for _ in 0..3 {
+            assert_eq!(processor.step(), StepOutcome::Exit(TIMEOUT_EXIT_CODE));
+        }
+        assert_eq!(processor.fuel(), Some(5));
+        assert_eq!(processor.exit_instruction(), Some(0));
+        processor.set_deadline(None);
+        processor.set_fuel(None);
+        assert_eq!(processor.exit_instruction(), None);
+        assert_eq!(processor.step_n(100), (StepOutcome::Continue, 100));
+    }
+
+    #[test]
+    fn exit_instruction() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // Selects stdout at the second instruction, and exits at the third as it doesn't push
+        let parser = Parser::new("형. 흑. 항");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        assert_eq!(processor.step_n(2), (StepOutcome::Continue, 2));
+        assert_eq!(processor.exit_instruction(), None);
+        assert_eq!(processor.advance(), Some(0));
+        assert_eq!(processor.exit_instruction(), Some(2));
+    }
+
+    #[test]
+    fn depth_limit() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // Pushes onto stack 3 forever
+        let parser = Parser::new("형.");
+        let mut stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        stacks.set_depth_limit(Some(100));
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        assert_eq!(
+            processor.step_n(1000).0,
+            StepOutcome::Exit(DEPTH_LIMIT_EXIT_CODE)
+        );
+        assert_eq!(processor.stack_manager().stack_len(3), Some(100));
+    }
+
+    #[test]
+    fn entropy_source() {
+        let source = include_str!("../snippets/hello-world.hyeong");
+        let run = |entropy: bool| {
+            let output = SharedWriter::new(vec![]);
+            {
+                let stdin = HyeongReadStack::new(&b""[..]);
+                let stdout = HyeongWriteStack::new(output.clone());
+                let stderr = HyeongWriteStack::new(vec![]);
+                let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+                let mut processor = Processor::with_stack_manager(Parser::new(source), stacks);
+                assert_eq!(processor.next_entropy(), None);
+                if entropy {
+                    let mut state = 0;
+                    processor.set_entropy_source(move || {
+                        state += 7;
+                        state
+                    });
+                    assert_eq!(processor.next_entropy(), Some(7));
+                    assert_eq!(processor.next_entropy(), Some(14));
+                }
+                processor.run().1.unwrap();
+            }
+            output.try_into_inner().unwrap()
+        };
+        // Deterministic by default, and unaffected by a source nothing consults
+        let expected = run(false);
+        assert_eq!(run(false), expected);
+        assert_eq!(run(true), expected);
+    }
+
+    #[test]
+    fn processor_is_send() {
+        // So that untrusted programs can run on worker threads, hooks included
+        fn assert_send<T: Send>() {}
+        assert_send::<Processor<Parser, std::fs::File, Vec<u8>, Vec<u8>>>();
+    }
+
+    #[test]
+    fn empty_program() {
+        for source in &["", " \n\t  \n"] {
+            let mut output = vec![];
+            let (exit_code, err) = {
+                let stdin = HyeongReadStack::new(&b""[..]);
+                let stdout = HyeongWriteStack::new(&mut output);
+                let stderr = HyeongWriteStack::new(vec![]);
+                let parser = Parser::new(source);
+                let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+                let processor = Processor::with_stack_manager(parser, stacks);
+                processor.run()
+            };
+            err.unwrap();
+            assert_eq!(exit_code, 0);
+            assert!(output.is_empty());
+        }
+    }
+
+    #[test]
+    fn step_owned() {
+        let mut output = vec![];
+        let exit_code = {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(&mut output);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let parser = Parser::new(include_str!(test_path!("hello-world", "hyeong")));
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let mut processor = Processor::with_stack_manager(parser, stacks);
+            loop {
+                let (next, outcome) = processor.step_owned();
+                processor = next;
+                if let StepOutcome::Exit(code) = outcome {
+                    break code;
+                }
+            }
+        };
+        assert_eq!(exit_code, include!(test_path!("hello-world", "exitcode")));
+        assert_eq!(
+            &output[..],
+            &include_bytes!(test_path!("hello-world", "stdout"))[..]
+        );
+    }
+
+    #[test]
+    fn step_n() {
+        let stdin = HyeongReadStack::new(&b""[..]);
+        let stdout = HyeongWriteStack::new(vec![]);
+        let stderr = HyeongWriteStack::new(vec![]);
+        // Ends on the fourth instruction, which is not a push while stdout is selected
+        let parser = Parser::new("형. 흑. 형. 항.");
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(parser, stacks);
+        assert_eq!(processor.step_n(0), (StepOutcome::Continue, 0));
+        assert_eq!(processor.step_n(2), (StepOutcome::Continue, 2));
+        assert_eq!(processor.step_n(5), (StepOutcome::Exit(0), 2));
+    }
 }
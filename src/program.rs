@@ -0,0 +1,89 @@
+use std::str::FromStr;
+
+use super::parser::{ParseError, Parser};
+use super::structure::Instruction;
+
+/// A fully parsed program.
+#[derive(Debug, PartialEq)]
+pub struct Program {
+    instructions: Vec<Instruction>,
+}
+
+impl Program {
+    /// Parses all of `source`, failing with the first malformed construct if there is any.
+    /// `Parser::errors` lists all of them.
+    pub fn parse(source: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser::new(source);
+        let instructions = parser.by_ref().collect();
+        match parser.errors().first() {
+            Some(error) => Err(error.clone()),
+            None => Ok(Program { instructions }),
+        }
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn into_instructions(self) -> Vec<Instruction> {
+        self.instructions
+    }
+}
+
+impl From<Vec<Instruction>> for Program {
+    fn from(instructions: Vec<Instruction>) -> Self {
+        Program { instructions }
+    }
+}
+
+impl TryFrom<&str> for Program {
+    type Error = ParseError;
+
+    fn try_from(source: &str) -> Result<Self, ParseError> {
+        Program::parse(source)
+    }
+}
+
+impl FromStr for Program {
+    type Err = ParseError;
+
+    fn from_str(source: &str) -> Result<Self, ParseError> {
+        Program::parse(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::parser::{ParseError, ParseErrorKind, Parser};
+    use super::Program;
+
+    #[test]
+    fn parse() {
+        let source = "형 항. 핫... 흡.. 흑. 흣.....";
+        let program: Program = source.try_into().unwrap();
+        assert_eq!(program.len(), 6);
+        assert_eq!(
+            program,
+            Program::from(Parser::new(source).collect::<Vec<_>>())
+        );
+        assert_eq!(source.parse::<Program>().unwrap(), program);
+        assert!("".parse::<Program>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_error() {
+        let expected = ParseError {
+            kind: ParseErrorKind::UnterminatedHangul('하'),
+            span: 11..14,
+        };
+        assert_eq!("형 혀엉 하 흐".parse::<Program>(), Err(expected));
+    }
+}
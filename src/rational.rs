@@ -2,75 +2,181 @@ use std::cmp::Ordering;
 use std::fmt::{self, Display, Formatter};
 use std::ops::{Add, AddAssign, Mul, MulAssign, Neg};
 
-use num_traits::{One, ToPrimitive, Zero};
+use num_traits::{CheckedAdd, CheckedMul, CheckedSub, One, ToPrimitive, Zero};
 
 #[cfg(feature = "big-rational")]
 use num_rational::BigRational as Rational;
-#[cfg(not(feature = "big-rational"))]
+#[cfg(all(feature = "rational-i128", not(feature = "big-rational")))]
+type Rational = num_rational::Ratio<i128>;
+#[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
 use num_rational::Rational64 as Rational;
 
+/// One past the largest Unicode scalar value. Values at or above it are printed as
+/// `너무 커엇...` instead of a character.
+pub const UNICODE_SCALAR_BOUND: u32 = 0x110000;
+
+/// What `HyeongRational::recip_with` does with the reciprocal of zero.
+#[derive(Clone, Debug, Default)]
+pub enum ReciprocalPolicy {
+    /// Yield NaN, as the language specifies.
+    #[default]
+    NaN,
+    /// Fail with `DivisionByZero`.
+    Error,
+    /// Yield the given value instead, e.g. a large number to saturate to.
+    Sentinel(HyeongRational),
+}
+
+/// The reciprocal of zero was taken under `ReciprocalPolicy::Error`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DivisionByZero;
+
+impl Display for DivisionByZero {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "reciprocal of zero")
+    }
+}
+
+impl std::error::Error for DivisionByZero {}
+
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HyeongRational {
     Rational(Rational),
     NaN,
 }
 
 impl HyeongRational {
-    #[cfg(feature = "big-rational")]
+    #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
     pub fn new_i64(numer: i64, denom: i64) -> HyeongRational {
         let r = Rational::new(numer.into(), denom.into());
         HyeongRational::Rational(r)
     }
-    #[cfg(not(feature = "big-rational"))]
+    #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
     pub fn new_i64(numer: i64, denom: i64) -> HyeongRational {
         let r = Rational::new(numer, denom);
         HyeongRational::Rational(r)
     }
-    #[cfg(feature = "big-rational")]
+    #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
     pub fn from_i64(value: i64) -> HyeongRational {
         let r = Rational::from_integer(value.into());
         HyeongRational::Rational(r)
     }
-    #[cfg(not(feature = "big-rational"))]
+    #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
     pub fn from_i64(value: i64) -> HyeongRational {
         let r = Rational::from_integer(value);
         HyeongRational::Rational(r)
     }
-    #[cfg(feature = "big-rational")]
+    #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
     pub fn from_u64(value: u64) -> HyeongRational {
         let r = Rational::from_integer(value.into());
         HyeongRational::Rational(r)
     }
-    #[cfg(not(feature = "big-rational"))]
+    #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
     pub fn from_u64(value: u64) -> HyeongRational {
         let r = Rational::from_integer(value as i64);
         HyeongRational::Rational(r)
     }
+    /// Converts `value` exactly, or gives NaN if the rational backend can't hold it. This
+    /// depends only on the enabled features, not on the target.
+    #[cfg(feature = "big-rational")]
+    pub fn from_u128(value: u128) -> HyeongRational {
+        let r = Rational::from_integer(value.into());
+        HyeongRational::Rational(r)
+    }
+    #[cfg(all(feature = "rational-i128", not(feature = "big-rational")))]
+    pub fn from_u128(value: u128) -> HyeongRational {
+        i128::try_from(value)
+            .ok()
+            .map(Rational::from_integer)
+            .into()
+    }
+    #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+    pub fn from_u128(value: u128) -> HyeongRational {
+        i64::try_from(value).ok().map(Rational::from_integer).into()
+    }
     pub fn is_nan(&self) -> bool {
         matches!(self, HyeongRational::NaN)
     }
+    /// Compares two values treating NaN as equal to NaN, unlike `PartialEq`.
+    pub fn structurally_eq(&self, other: &HyeongRational) -> bool {
+        match (self, other) {
+            (HyeongRational::NaN, HyeongRational::NaN) => true,
+            (HyeongRational::Rational(l), HyeongRational::Rational(r)) => l == r,
+            _ => false,
+        }
+    }
+    /// `structurally_eq` over slices of the same length.
+    pub fn slices_structurally_eq(lhs: &[HyeongRational], rhs: &[HyeongRational]) -> bool {
+        lhs.len() == rhs.len() && lhs.iter().zip(rhs).all(|(l, r)| l.structurally_eq(r))
+    }
+    /// The inner value, or `None` if this is NaN.
+    pub fn as_rational(&self) -> Option<&Rational> {
+        match self {
+            HyeongRational::NaN => None,
+            HyeongRational::Rational(r) => Some(r),
+        }
+    }
+    /// The inner value, or `None` if this is NaN.
+    pub fn try_into_rational(self) -> Option<Rational> {
+        self.into()
+    }
+    /// The inner value. Panics if this is NaN; see `as_rational` for a non-panicking version.
     pub fn rational(&self) -> &Rational {
         match self {
             HyeongRational::NaN => panic!("the value is NaN"),
             HyeongRational::Rational(r) => r,
         }
     }
+    /// The inner value. Panics if this is NaN; see `try_into_rational` for a non-panicking
+    /// version.
     pub fn into_rational(self) -> Rational {
         match self {
             HyeongRational::NaN => panic!("the value is NaN"),
             HyeongRational::Rational(r) => r,
         }
     }
+    /// The character a write stack prints for this value, i.e. the floor of a non-negative
+    /// value below `UNICODE_SCALAR_BOUND` which is not a surrogate.
+    pub fn to_char(&self) -> Option<char> {
+        let int = match self {
+            HyeongRational::NaN => return None,
+            HyeongRational::Rational(r) => r.floor().to_integer(),
+        };
+        int.to_u32()
+            .filter(|&c| c < UNICODE_SCALAR_BOUND)
+            .and_then(std::char::from_u32)
+    }
+    /// Whether a write stack prints this value as a character, rather than as `너무 커엇...` or
+    /// as the absolute value of a negative number.
+    pub fn is_printable_char(&self) -> bool {
+        self.to_char().is_some()
+    }
+    /// The reciprocal, or NaN for zero. Also NaN if a fixed-width backend can't hold it, as
+    /// for `i64::MIN`, whose reciprocal would need its magnitude in the denominator.
     pub fn recip(&self) -> HyeongRational {
         match self {
             HyeongRational::NaN => HyeongRational::NaN,
-            HyeongRational::Rational(r) => {
-                if r.is_zero() {
-                    HyeongRational::NaN
-                } else {
-                    r.recip().into()
-                }
-            }
+            HyeongRational::Rational(r) if r.is_zero() => HyeongRational::NaN,
+            // Taking the reciprocal moves the sign to the numerator by negating both parts,
+            // which overflows for the minimum numerator; go through the checked negation
+            HyeongRational::Rational(r) if *r < Rational::zero() => match -self.clone() {
+                HyeongRational::Rational(magnitude) => -HyeongRational::from(magnitude.recip()),
+                HyeongRational::NaN => HyeongRational::NaN,
+            },
+            HyeongRational::Rational(r) => r.recip().into(),
+        }
+    }
+    /// Like `recip`, but the reciprocal of zero is decided by `policy`. The reciprocal of NaN
+    /// is still NaN.
+    pub fn recip_with(&self, policy: ReciprocalPolicy) -> Result<HyeongRational, DivisionByZero> {
+        match (self, policy) {
+            (HyeongRational::Rational(r), policy) if r.is_zero() => match policy {
+                ReciprocalPolicy::NaN => Ok(HyeongRational::NaN),
+                ReciprocalPolicy::Error => Err(DivisionByZero),
+                ReciprocalPolicy::Sentinel(value) => Ok(value),
+            },
+            _ => Ok(self.recip()),
         }
     }
 }
@@ -90,6 +196,12 @@ impl From<Rational> for HyeongRational {
     }
 }
 
+impl From<char> for HyeongRational {
+    fn from(item: char) -> HyeongRational {
+        HyeongRational::from_u64(item as u64)
+    }
+}
+
 impl From<Option<Rational>> for HyeongRational {
     fn from(item: Option<Rational>) -> HyeongRational {
         match item {
@@ -115,12 +227,10 @@ impl Display for HyeongRational {
                 let int = r.floor().to_integer();
                 let zero = Zero::zero();
                 if int >= zero {
-                    let unicode_bound = num_traits::FromPrimitive::from_i64(0x110000).unwrap();
-                    if int >= unicode_bound {
-                        write!(f, "너무 커엇...")
-                    } else {
-                        let int = int.to_u32().and_then(::std::char::from_u32).unwrap();
-                        write!(f, "{}", int)
+                    // Surrogates aren't characters either
+                    match self.to_char() {
+                        Some(c) => write!(f, "{}", c),
+                        None => write!(f, "너무 커엇..."),
                     }
                 } else {
                     write!(f, "{}", -int)
@@ -157,7 +267,10 @@ impl Add for HyeongRational {
         if self.is_nan() || rhs.is_nan() {
             return HyeongRational::NaN;
         }
-        (self.into_rational() + rhs.into_rational()).into()
+        // Fixed-width backends give NaN instead of overflowing, like `from_u128` does
+        self.into_rational()
+            .checked_add(&rhs.into_rational())
+            .into()
     }
 }
 
@@ -174,7 +287,9 @@ impl Mul for HyeongRational {
         if self.is_nan() || rhs.is_nan() {
             return HyeongRational::NaN;
         }
-        (self.into_rational() * rhs.into_rational()).into()
+        self.into_rational()
+            .checked_mul(&rhs.into_rational())
+            .into()
     }
 }
 
@@ -190,7 +305,7 @@ impl Neg for HyeongRational {
     fn neg(self) -> Self::Output {
         match self {
             HyeongRational::NaN => HyeongRational::NaN,
-            HyeongRational::Rational(r) => (-r).into(),
+            HyeongRational::Rational(r) => Rational::zero().checked_sub(&r).into(),
         }
     }
 }
@@ -216,7 +331,7 @@ impl One for HyeongRational {
 
 #[cfg(test)]
 mod tests {
-    use super::HyeongRational;
+    use super::{DivisionByZero, HyeongRational, ReciprocalPolicy};
 
     #[test]
     fn partial_eq() {
@@ -232,6 +347,53 @@ mod tests {
         assert_ne!(nan, another_nan); // NaN != NaN
     }
     #[test]
+    fn structurally_eq() {
+        let three = HyeongRational::from_u64(3);
+        let five = HyeongRational::from_u64(5);
+        let nan = HyeongRational::NaN;
+
+        assert!(three.structurally_eq(&HyeongRational::from_u64(3)));
+        assert!(!three.structurally_eq(&five));
+        assert!(!three.structurally_eq(&nan));
+        assert!(nan.structurally_eq(&HyeongRational::NaN));
+
+        let stack = vec![three.clone(), nan.clone(), five.clone()];
+        assert!(HyeongRational::slices_structurally_eq(
+            &stack,
+            &[three.clone(), nan.clone(), five.clone()]
+        ));
+        assert!(!HyeongRational::slices_structurally_eq(
+            &stack,
+            &[three.clone(), five.clone(), nan.clone()]
+        ));
+        assert!(!HyeongRational::slices_structurally_eq(
+            &stack,
+            &[three, nan]
+        ));
+    }
+    #[test]
+    fn to_char() {
+        use super::UNICODE_SCALAR_BOUND;
+
+        let bound = UNICODE_SCALAR_BOUND as u64;
+        assert_eq!(HyeongRational::from('하').to_char(), Some('하'));
+        assert_eq!(HyeongRational::new_i64(65 * 2 + 1, 2).to_char(), Some('A'));
+        assert_eq!(
+            HyeongRational::from_u64(bound - 1).to_char(),
+            Some('\u{10ffff}')
+        );
+        assert_eq!(HyeongRational::from_u64(bound).to_char(), None);
+        assert_eq!(HyeongRational::from_u64(0xd800).to_char(), None);
+        assert_eq!(HyeongRational::from_i64(-1).to_char(), None);
+        assert_eq!(HyeongRational::NaN.to_char(), None);
+        assert!(HyeongRational::from_u64(bound - 1).is_printable_char());
+        assert!(!HyeongRational::from_u64(bound).is_printable_char());
+        assert!(!HyeongRational::new_i64(-1, 2).is_printable_char());
+
+        assert_eq!(HyeongRational::from_u64(bound).to_string(), "너무 커엇...");
+        assert_eq!(HyeongRational::from_u64(0xd800).to_string(), "너무 커엇...");
+    }
+    #[test]
     fn partial_ord() {
         use std::cmp::Ordering;
 
@@ -284,4 +446,82 @@ mod tests {
         assert!(zero.recip().is_nan());
         assert!(nan.recip().is_nan());
     }
+    #[test]
+    fn overflow() {
+        let max = HyeongRational::from_i64(i64::MAX);
+        let min = HyeongRational::from_i64(i64::MIN);
+        let sum = max.clone() + max.clone();
+        let product = max.clone() * max;
+        #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+        {
+            assert!(sum.is_nan());
+            assert!(product.is_nan());
+            assert!((-min.clone()).is_nan());
+            assert!(min.recip().is_nan());
+        }
+        #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+        {
+            assert_eq!(sum, HyeongRational::from_u128(i64::MAX as u128 * 2));
+            assert!(!product.is_nan());
+            assert!(!(-min.clone()).is_nan());
+            assert_eq!(
+                min.recip(),
+                HyeongRational::new_i64(-1, 1 << 62) * HyeongRational::new_i64(1, 2)
+            );
+        }
+        assert_eq!(
+            HyeongRational::new_i64(-2, 3).recip(),
+            HyeongRational::new_i64(-3, 2)
+        );
+    }
+    #[test]
+    fn from_u128() {
+        let max = HyeongRational::from_i64(i64::MAX);
+        assert_eq!(HyeongRational::from_u128(i64::MAX as u128), max);
+        let beyond = HyeongRational::from_u128(1 << 63);
+        #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+        assert!(beyond.is_nan());
+        #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+        assert_eq!(beyond, max + HyeongRational::from_u64(1));
+        let huge = HyeongRational::from_u128(u128::MAX);
+        #[cfg(not(feature = "big-rational"))]
+        assert!(huge.is_nan());
+        #[cfg(feature = "big-rational")]
+        assert!(!huge.is_nan());
+    }
+    #[test]
+    fn as_rational() {
+        let half = HyeongRational::new_i64(1, 2);
+        assert_eq!(half.as_rational(), Some(half.rational()));
+        assert_eq!(half.clone().try_into_rational(), Some(half.into_rational()));
+        assert_eq!(HyeongRational::NaN.as_rational(), None);
+        assert_eq!(HyeongRational::NaN.try_into_rational(), None);
+    }
+    #[test]
+    fn recip_with() {
+        let two = HyeongRational::from_u64(2);
+        let zero = HyeongRational::from_u64(0);
+        let big = HyeongRational::from_i64(i64::MAX);
+        let sentinel = || ReciprocalPolicy::Sentinel(big.clone());
+
+        assert!(zero.recip_with(ReciprocalPolicy::NaN).unwrap().is_nan());
+        assert_eq!(
+            zero.recip_with(ReciprocalPolicy::Error),
+            Err(DivisionByZero)
+        );
+        assert_eq!(zero.recip_with(sentinel()).unwrap(), big);
+        for policy in [ReciprocalPolicy::NaN, ReciprocalPolicy::Error, sentinel()] {
+            assert_eq!(two.recip_with(policy.clone()).unwrap(), two.recip());
+            assert!(HyeongRational::NaN.recip_with(policy).unwrap().is_nan());
+        }
+    }
+    #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+    #[test]
+    fn beyond_i64() {
+        let max = HyeongRational::from_i64(i64::MAX);
+        let four = HyeongRational::from_u64(4);
+        let big = max.clone() * four.clone();
+        assert!(big > max);
+        assert_eq!(big * four.recip(), max);
+    }
 }
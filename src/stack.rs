@@ -1,15 +1,30 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::io::prelude::*;
+use std::num::NonZeroUsize;
+use std::rc::Rc;
 
-use num_traits::{One, Zero};
+use num_traits::{One, Signed, Zero};
 
 use super::rational::HyeongRational;
 use super::structure::HeartTree;
 use super::utf8::read_codepoint;
 
+/// A stack as the operations see it. Numbered stacks are `Vec`s unless another implementation
+/// is registered with `StackManager::register_stack`.
 pub trait HyeongStack {
     fn push_one(&mut self, value: HyeongRational);
+    /// Pops the top value, or gives NaN if there is none.
     fn pop_one(&mut self) -> HyeongRational;
+
+    /// Pushes `values` in order, as if by `push_one` on each of them.
+    fn push_many(&mut self, values: &[HyeongRational]) {
+        for value in values {
+            self.push_one(value.clone());
+        }
+    }
 }
 
 impl HyeongStack for Vec<HyeongRational> {
@@ -17,23 +32,133 @@ impl HyeongStack for Vec<HyeongRational> {
         self.push(value);
     }
 
+    fn push_many(&mut self, values: &[HyeongRational]) {
+        self.extend_from_slice(values);
+    }
+
     fn pop_one(&mut self) -> HyeongRational {
         self.pop().into()
     }
 }
 
+/// How a read stack turns its input into values.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum InputMode {
+    /// Each UTF-8 encoded character is one value, as the language specifies.
+    #[default]
+    Utf8,
+    /// Each byte is one value from 0 to 255.
+    Bytes,
+}
+
+/// A reader which counts the bytes read through it.
+#[derive(Clone, Debug, Default)]
+pub struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R> CountingReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+}
+
 pub struct HyeongReadStack<R> {
     inner: R,
+    mode: InputMode,
     stack: Vec<HyeongRational>,
+    bytes_consumed: u64,
+    values_consumed: u64,
+    eof_reads: usize,
+    eof_limit: Option<NonZeroUsize>,
 }
 
 impl<R> HyeongReadStack<R> {
     pub fn new(inner: R) -> Self {
+        Self::with_mode(inner, InputMode::Utf8)
+    }
+
+    pub fn new_bytes(inner: R) -> Self {
+        Self::with_mode(inner, InputMode::Bytes)
+    }
+
+    pub fn with_mode(inner: R, mode: InputMode) -> Self {
         Self {
             inner,
+            mode,
             stack: vec![],
+            bytes_consumed: 0,
+            values_consumed: 0,
+            eof_reads: 0,
+            eof_limit: None,
         }
     }
+
+    /// Whether the last read from the input failed, because it ran out or was malformed.
+    pub fn at_eof(&self) -> bool {
+        self.eof_reads > 0
+    }
+
+    /// Gives up after `reads` consecutive reads past the end of input, which all yield NaN; a
+    /// `StackManager` then ends the program with `EOF_LIMIT_EXIT_CODE`. This stops programs
+    /// which would otherwise spin on NaN forever once input runs out. The limit can't be zero,
+    /// which would end programs that never read at all.
+    pub fn set_eof_limit(&mut self, reads: Option<NonZeroUsize>) {
+        self.eof_limit = reads;
+    }
+
+    pub fn eof_limit_reached(&self) -> bool {
+        self.eof_limit
+            .is_some_and(|limit| self.eof_reads >= limit.get())
+    }
+
+    /// Bytes read from the input so far, including those of a malformed or truncated character.
+    /// Values pushed back onto the stack don't count.
+    pub fn bytes_consumed(&self) -> u64 {
+        self.bytes_consumed
+    }
+
+    /// Values read from the input so far: characters, or bytes with `InputMode::Bytes`. Reads
+    /// which failed and values pushed back onto the stack don't count.
+    pub fn values_consumed(&self) -> u64 {
+        self.values_consumed
+    }
+}
+
+impl<R: Read> HyeongReadStack<R> {
+    fn read_value(&mut self) -> std::io::Result<u32> {
+        let mut reader = CountingReader::new(&mut self.inner);
+        let value = match self.mode {
+            InputMode::Utf8 => read_codepoint(&mut reader),
+            InputMode::Bytes => {
+                let mut buf = [0];
+                reader.read_exact(&mut buf).map(|_| buf[0] as u32)
+            }
+        };
+        self.bytes_consumed += reader.count();
+        value
+    }
 }
 
 impl<R: Read> HyeongStack for HyeongReadStack<R> {
@@ -41,11 +166,18 @@ impl<R: Read> HyeongStack for HyeongReadStack<R> {
         self.stack.push_one(value);
     }
 
+    fn push_many(&mut self, values: &[HyeongRational]) {
+        self.stack.push_many(values);
+    }
+
     fn pop_one(&mut self) -> HyeongRational {
         if self.stack.is_empty() {
-            if let Ok(c) = read_codepoint(&mut self.inner) {
+            if let Ok(c) = self.read_value() {
+                self.eof_reads = 0;
+                self.values_consumed += 1;
                 HyeongRational::from_u64(c as u64)
             } else {
+                self.eof_reads += 1;
                 HyeongRational::NaN
             }
         } else {
@@ -54,32 +186,348 @@ impl<R: Read> HyeongStack for HyeongReadStack<R> {
     }
 }
 
+/// How a write stack renders the values pushed onto it.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum OutputMode {
+    /// Floors each value and prints it as a character, as the language specifies.
+    #[default]
+    Char,
+    /// Like `Char`, but non-integers are printed as an exact `p/q` fraction.
+    Rational,
+}
+
+/// How a write stack renders values below zero. Interpreters disagree on this.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum NegativeMode {
+    /// Prints the absolute value of the floor as a decimal integer, as the language specifies.
+    #[default]
+    Absolute,
+    /// Prints `너무 커엇...`, like values which aren't characters.
+    Marker,
+    /// Prints nothing.
+    Omit,
+}
+
+/// The exit code of a program stopped for going over the output limit of a write stack.
+pub const OUTPUT_LIMIT_EXIT_CODE: isize = -1;
+
+/// The exit code of a program stopped for reading past the end of input too many times.
+pub const EOF_LIMIT_EXIT_CODE: isize = -2;
+
+/// The exit code of a program stopped for going over the depth limit of a numbered stack.
+pub const DEPTH_LIMIT_EXIT_CODE: isize = -3;
+
+/// The exit code of a program stopped for writing to stderr while that is fatal.
+pub const STDERR_EXIT_CODE: isize = -5;
+
+/// The exit code of a program stopped for computing NaN while that is fatal.
+pub const NAN_EXIT_CODE: isize = -6;
+
+/// The exit code of a program stopped because writing its output failed. The error is returned
+/// by the next `StackManager::flush`.
+pub const WRITE_ERROR_EXIT_CODE: isize = -9;
+
+/// How much a write stack has emitted.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct WriteStats {
+    pub codepoints: usize,
+    pub bytes: usize,
+}
+
 pub struct HyeongWriteStack<W> {
     inner: W,
+    mode: OutputMode,
+    negative: NegativeMode,
+    stats: WriteStats,
+    strict: bool,
+    pop_attempts: usize,
+    limit: Option<usize>,
+    limit_exceeded: bool,
+    captured: Option<Vec<u8>>,
+    write_failed: bool,
+    write_error: Option<std::io::Error>,
 }
 
 impl<W> HyeongWriteStack<W> {
     pub fn new(inner: W) -> Self {
-        Self { inner }
+        Self::with_mode(inner, OutputMode::Char)
+    }
+
+    pub fn with_mode(inner: W, mode: OutputMode) -> Self {
+        Self {
+            inner,
+            mode,
+            negative: NegativeMode::Absolute,
+            stats: WriteStats::default(),
+            strict: false,
+            pop_attempts: 0,
+            limit: None,
+            limit_exceeded: false,
+            captured: None,
+            write_failed: false,
+            write_error: None,
+        }
+    }
+
+    /// Creates a write stack which emits at most `max_bytes` bytes. See `set_limit`.
+    pub fn with_limit(inner: W, max_bytes: usize) -> Self {
+        let mut stack = Self::new(inner);
+        stack.set_limit(Some(max_bytes));
+        stack
+    }
+
+    /// Caps the number of bytes emitted. A value which would go over the cap is not written,
+    /// and nothing is written from then on; a `StackManager` then ends the program with
+    /// `OUTPUT_LIMIT_EXIT_CODE`.
+    pub fn set_limit(&mut self, max_bytes: Option<usize>) {
+        self.limit = max_bytes;
+    }
+
+    pub fn limit_exceeded(&self) -> bool {
+        self.limit_exceeded
+    }
+
+    /// Whether writing to the underlying writer has failed. Nothing is written after a failure,
+    /// and a `StackManager` ends the program with `WRITE_ERROR_EXIT_CODE`.
+    pub fn write_failed(&self) -> bool {
+        self.write_failed
+    }
+
+    /// Takes the error writing failed with, if it hasn't been taken yet. Flushing takes it too.
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.write_error.take()
+    }
+
+    /// Starts keeping a copy of everything emitted from now on, in addition to writing it.
+    pub fn enable_capture(&mut self) {
+        self.captured.get_or_insert_with(Vec::new);
+    }
+
+    /// Everything emitted since capturing was enabled, if it is.
+    pub fn captured(&self) -> Option<&[u8]> {
+        self.captured.as_deref()
+    }
+
+    /// In strict mode, popping is recorded in `pop_attempts`. Popping from an output stack
+    /// yields NaN either way, but it is almost always a mistake in the program.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// How many times the stack was popped while in strict mode.
+    pub fn pop_attempts(&self) -> usize {
+        self.pop_attempts
+    }
+
+    pub fn set_mode(&mut self, mode: OutputMode) {
+        self.mode = mode;
+    }
+
+    /// Sets how negative values are printed. Non-integers in `OutputMode::Rational` are always
+    /// printed as signed fractions.
+    pub fn set_negative_mode(&mut self, negative: NegativeMode) {
+        self.negative = negative;
+    }
+
+    pub fn stats(&self) -> WriteStats {
+        self.stats
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
     }
 }
 
 impl<W: Write> HyeongWriteStack<W> {
+    /// Returns the error a write failed with, if any, instead of flushing.
     fn flush(&mut self) -> std::io::Result<()> {
-        self.inner.flush()
+        match self.write_error.take() {
+            Some(e) => Err(e),
+            None if self.write_failed => Ok(()),
+            None => self.inner.flush(),
+        }
+    }
+}
+
+impl<W: Write> HyeongWriteStack<W> {
+    fn render(&self, value: &HyeongRational) -> String {
+        match (self.mode, value) {
+            (OutputMode::Rational, HyeongRational::Rational(r)) if !r.is_integer() => r.to_string(),
+            (_, HyeongRational::Rational(r)) if r.is_negative() => match self.negative {
+                NegativeMode::Absolute => value.to_string(),
+                NegativeMode::Marker => HyeongRational::NaN.to_string(),
+                NegativeMode::Omit => String::new(),
+            },
+            _ => value.to_string(),
+        }
+    }
+
+    /// Writes `s` and counts it as written, unless an earlier write failed. A failed write isn't
+    /// counted, in the stats nor against the limit.
+    fn emit(&mut self, s: &str) {
+        if self.write_failed {
+            return;
+        }
+        if let Err(e) = self.inner.write_all(s.as_bytes()) {
+            self.write_failed = true;
+            self.write_error = Some(e);
+            return;
+        }
+        self.stats.codepoints += s.chars().count();
+        self.stats.bytes += s.len();
+        if let Some(captured) = &mut self.captured {
+            captured.extend_from_slice(s.as_bytes());
+        }
+    }
+
+    /// Whether `len` more bytes fit under the limit, after `pending` bytes about to be emitted.
+    fn fits(&mut self, pending: usize, len: usize) -> bool {
+        let total = self.stats.bytes + pending + len;
+        if self.limit_exceeded || self.limit.is_some_and(|l| total > l) {
+            self.limit_exceeded = true;
+            return false;
+        }
+        true
     }
 }
 
 impl<W: Write> HyeongStack for HyeongWriteStack<W> {
     fn push_one(&mut self, value: HyeongRational) {
-        write!(&mut self.inner, "{}", value).unwrap();
+        let s = self.render(&value);
+        if self.fits(0, s.len()) {
+            self.emit(&s);
+        }
+    }
+
+    /// Renders all of `values` first and writes them at once.
+    fn push_many(&mut self, values: &[HyeongRational]) {
+        let mut buf = String::new();
+        for value in values {
+            let s = self.render(value);
+            if !self.fits(buf.len(), s.len()) {
+                break;
+            }
+            buf.push_str(&s);
+        }
+        self.emit(&buf);
     }
 
     fn pop_one(&mut self) -> HyeongRational {
+        if self.strict {
+            self.pop_attempts += 1;
+        }
         HyeongRational::NaN
     }
 }
 
+/// An in-memory writer that collects output into a `String`.
+///
+/// Write stacks only ever emit valid UTF-8, including the `너무 커엇...` marker, so no
+/// conversion from bytes is needed afterwards.
+#[derive(Clone, Debug, Default)]
+pub struct StringWriter {
+    buf: String,
+}
+
+impl StringWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.buf
+    }
+
+    pub fn into_string(self) -> String {
+        self.buf
+    }
+}
+
+impl Write for StringWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let s = std::str::from_utf8(buf)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        self.buf.push_str(s);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A writer which hands everything written to a callback, e.g. to stream output to a UI as it
+/// is produced.
+///
+/// A write stack emits a value pushed on its own with a single write, so the callback gets the
+/// encoded bytes of one value at a time. Values pushed together, as `StackManager::push_values`
+/// and `clone_stack` do, are emitted with a single write for all of them.
+pub struct CallbackWriter<F> {
+    callback: F,
+}
+
+impl<F: FnMut(&[u8])> CallbackWriter<F> {
+    pub fn new(callback: F) -> Self {
+        Self { callback }
+    }
+}
+
+impl<F: FnMut(&[u8])> Write for CallbackWriter<F> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (self.callback)(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A writer which can be handed to more than one write stack, e.g. to merge stderr into stdout.
+///
+/// Clones write to the same underlying writer, so interleaved writes keep their order.
+#[derive(Debug, Default)]
+pub struct SharedWriter<W> {
+    inner: Rc<RefCell<W>>,
+}
+
+impl<W> SharedWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(inner)),
+        }
+    }
+
+    /// Returns the underlying writer if this is the last clone, or `self` otherwise.
+    pub fn try_into_inner(self) -> Result<W, Self> {
+        Rc::try_unwrap(self.inner)
+            .map(RefCell::into_inner)
+            .map_err(|inner| Self { inner })
+    }
+}
+
+impl<W> Clone for SharedWriter<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.borrow_mut().flush()
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum HeartResult {
     Heart(u64),
@@ -87,13 +535,154 @@ pub enum HeartResult {
     Nil,
 }
 
+impl From<HeartResult> for HeartTree {
+    fn from(v: HeartResult) -> HeartTree {
+        match v {
+            HeartResult::Heart(id) => HeartTree::Heart(id),
+            HeartResult::Return => HeartTree::Return,
+            HeartResult::Nil => HeartTree::Nil,
+        }
+    }
+}
+
+/// Leaves convert to the result they evaluate to; branches give `None`.
+impl From<&HeartTree> for Option<HeartResult> {
+    fn from(v: &HeartTree) -> Option<HeartResult> {
+        match *v {
+            HeartTree::Heart(id) => Some(HeartResult::Heart(id)),
+            HeartTree::Return => Some(HeartResult::Return),
+            HeartTree::Nil => Some(HeartResult::Nil),
+            HeartTree::LessThan(..) | HeartTree::Equals(..) => None,
+        }
+    }
+}
+
+/// The state of a `StackManager`, apart from its IO streams.
+///
+/// Comparison treats NaN as equal to NaN, so a snapshot can be compared against an expected
+/// state.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StackSnapshot {
+    pub selected: u64,
+    /// Contents of the numbered stacks, bottom first. Empty stacks are left out.
+    pub stacks: BTreeMap<u64, Vec<HyeongRational>>,
+    /// Values pushed back onto the read stack, bottom first.
+    pub input: Vec<HyeongRational>,
+}
+
+impl PartialEq for StackSnapshot {
+    fn eq(&self, other: &StackSnapshot) -> bool {
+        self.selected == other.selected
+            && HyeongRational::slices_structurally_eq(&self.input, &other.input)
+            && self.stacks.len() == other.stacks.len()
+            && self
+                .stacks
+                .iter()
+                .zip(&other.stacks)
+                .all(|((li, l), (ri, r))| li == ri && HyeongRational::slices_structurally_eq(l, r))
+    }
+}
+
+/// Which stack ids are special. The default is the layout the language specifies.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StackLayout {
+    pub stdin: u64,
+    pub stdout: u64,
+    pub stderr: u64,
+    /// The stack selected when the program starts.
+    pub start: u64,
+    /// The exit code when an operation other than push runs with stdout selected.
+    pub stdout_exit_code: isize,
+    /// The exit code when an operation other than push runs with stderr selected.
+    pub stderr_exit_code: isize,
+}
+
+impl Default for StackLayout {
+    fn default() -> Self {
+        StackLayout {
+            stdin: 0,
+            stdout: 1,
+            stderr: 2,
+            start: 3,
+            stdout_exit_code: 0,
+            stderr_exit_code: 1,
+        }
+    }
+}
+
+impl StackLayout {
+    /// Whether `id` is one of the IO stacks.
+    pub fn is_io(&self, id: u64) -> bool {
+        id == self.stdin || id == self.stdout || id == self.stderr
+    }
+}
+
+// Lists values as exact rationals, e.g. `[3, -1/2, NaN]`
+struct ValuesDebug<'a>(&'a [HyeongRational]);
+
+impl fmt::Debug for ValuesDebug<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        struct Value<'a>(&'a HyeongRational);
+        impl fmt::Debug for Value<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match self.0 {
+                    HyeongRational::Rational(r) => write!(f, "{}", r),
+                    HyeongRational::NaN => write!(f, "NaN"),
+                }
+            }
+        }
+        f.debug_list().entries(self.0.iter().map(Value)).finish()
+    }
+}
+
 pub struct StackManager<I, O, E> {
     stdin: HyeongReadStack<I>,
     stdout: HyeongWriteStack<O>,
     stderr: HyeongWriteStack<E>,
     stacks: HashMap<u64, Vec<HyeongRational>>,
+    layout: StackLayout,
     selected: u64,
     exit_code: Option<isize>,
+    depth_limit: Option<usize>,
+    depth_exceeded: bool,
+    custom: HashMap<u64, Box<dyn HyeongStack + Send>>,
+    overflows: u64,
+    fatal_stderr: bool,
+    stderr_written: bool,
+    stack_created: Option<Box<dyn FnMut(u64) + Send>>,
+    fatal_nan: bool,
+    nan_computed: bool,
+}
+
+/// Shows the selected stack, the numbered stacks in order and the values pushed back onto
+/// stdin, with values as exact rationals.
+impl<I, O, E> fmt::Debug for StackManager<I, O, E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let stacks = self
+            .stacks
+            .iter()
+            .map(|(id, stack)| (id, ValuesDebug(stack)))
+            .collect::<BTreeMap<_, _>>();
+        f.debug_struct("StackManager")
+            .field("selected", &self.selected)
+            .field("stacks", &stacks)
+            .field("input", &ValuesDebug(&self.stdin.stack))
+            .field("exit_code", &self.exit_code)
+            .finish()
+    }
+}
+
+impl<'a> StackManager<&'a [u8], StringWriter, StringWriter> {
+    /// Creates a stack manager reading `input` and collecting stdout and stderr as strings,
+    /// which `stdout().get_ref()` and `stderr().get_ref()` or `into_io` give back.
+    pub fn in_memory(input: &'a str) -> Self {
+        Self::from_stacks(
+            HyeongReadStack::new(input.as_bytes()),
+            HyeongWriteStack::new(StringWriter::new()),
+            HyeongWriteStack::new(StringWriter::new()),
+        )
+    }
 }
 
 impl<I: Read, O: Write, E: Write> StackManager<I, O, E> {
@@ -102,24 +691,111 @@ impl<I: Read, O: Write, E: Write> StackManager<I, O, E> {
         stdout: HyeongWriteStack<O>,
         stderr: HyeongWriteStack<E>,
     ) -> Self {
-        let mut stacks = HashMap::new();
-        stacks.insert(3, vec![]);
-        Self {
+        Self::with_layout(stdin, stdout, stderr, StackLayout::default())
+    }
+
+    /// Creates a stack manager with different stack ids for IO and the starting stack.
+    ///
+    /// Panics if the IO stacks don't have distinct ids.
+    pub fn with_layout(
+        stdin: HyeongReadStack<I>,
+        stdout: HyeongWriteStack<O>,
+        stderr: HyeongWriteStack<E>,
+        layout: StackLayout,
+    ) -> Self {
+        assert!(
+            layout.stdin != layout.stdout
+                && layout.stdin != layout.stderr
+                && layout.stdout != layout.stderr,
+            "IO stacks must have distinct ids"
+        );
+        let mut manager = Self {
             stdin,
             stdout,
             stderr,
-            stacks,
-            selected: 3,
+            stacks: HashMap::new(),
+            layout,
+            selected: layout.start,
             exit_code: None,
+            depth_limit: None,
+            depth_exceeded: false,
+            custom: HashMap::new(),
+            overflows: 0,
+            fatal_stderr: false,
+            stderr_written: false,
+            stack_created: None,
+            fatal_nan: false,
+            nan_computed: false,
+        };
+        manager.make_stack(layout.start);
+        manager
+    }
+
+    /// Gives back stdin, stdout and stderr, dropping every other stack. Nothing is flushed.
+    pub fn into_io(self) -> (HyeongReadStack<I>, HyeongWriteStack<O>, HyeongWriteStack<E>) {
+        (self.stdin, self.stdout, self.stderr)
+    }
+
+    pub fn layout(&self) -> StackLayout {
+        self.layout
+    }
+
+    /// Id of the selected stack.
+    pub fn selected(&self) -> u64 {
+        self.selected
+    }
+
+    pub fn snapshot(&self) -> StackSnapshot {
+        StackSnapshot {
+            selected: self.selected,
+            stacks: self
+                .stacks
+                .iter()
+                .filter(|(_, stack)| !stack.is_empty())
+                .map(|(&id, stack)| (id, stack.clone()))
+                .collect(),
+            input: self.stdin.stack.clone(),
         }
     }
 
+    /// Replaces the selected stack and the contents of every stack with those of `snapshot`.
+    pub fn restore(&mut self, snapshot: &StackSnapshot) {
+        self.stacks.clear();
+        self.stacks.extend(
+            snapshot
+                .stacks
+                .iter()
+                .map(|(&id, stack)| (id, stack.clone())),
+        );
+        self.stdin.stack = snapshot.input.clone();
+        self.selected = snapshot.selected;
+        self.make_stack(self.selected);
+    }
+
+    /// Starts capturing what stdout and stderr emit. See `HyeongWriteStack::enable_capture`.
+    pub fn enable_capture(&mut self) {
+        self.stdout.enable_capture();
+        self.stderr.enable_capture();
+    }
+
+    pub fn stdin(&self) -> &HyeongReadStack<I> {
+        &self.stdin
+    }
+
+    pub fn stdout(&self) -> &HyeongWriteStack<O> {
+        &self.stdout
+    }
+
+    pub fn stderr(&self) -> &HyeongWriteStack<E> {
+        &self.stderr
+    }
+
     fn check_exit(&mut self) -> bool {
-        if self.selected == 1 {
-            self.exit_code = Some(0);
+        if self.selected == self.layout.stdout {
+            self.exit_code = Some(self.layout.stdout_exit_code);
             true
-        } else if self.selected == 2 {
-            self.exit_code = Some(1);
+        } else if self.selected == self.layout.stderr {
+            self.exit_code = Some(self.layout.stderr_exit_code);
             true
         } else {
             false
@@ -133,91 +809,305 @@ impl<I: Read, O: Write, E: Write> StackManager<I, O, E> {
 
     fn stack_mut(&mut self, id: u64) -> &mut dyn HyeongStack {
         self.make_stack(id);
-        match id {
-            0 => &mut self.stdin,
-            1 => &mut self.stdout,
-            2 => &mut self.stderr,
-            i => self.stacks.get_mut(&i).unwrap(),
+        if id == self.layout.stdin {
+            &mut self.stdin
+        } else if id == self.layout.stdout {
+            &mut self.stdout
+        } else if id == self.layout.stderr {
+            &mut self.stderr
+        } else if let Some(stack) = self.custom.get_mut(&id) {
+            stack.as_mut()
+        } else {
+            self.stacks.get_mut(&id).unwrap()
+        }
+    }
+
+    /// Uses `stack` as the numbered stack `id` from now on, e.g. to give it different
+    /// semantics in a dialect. Values already on the stack are pushed onto `stack`, bottom
+    /// first.
+    ///
+    /// Registered stacks are opaque: `stack_len` gives `None` for them, snapshots leave them
+    /// out, and neither `clear_stack`, `clone_stack` nor the depth limit applies to them.
+    ///
+    /// `stack` must be `Send`, so that the manager still is.
+    ///
+    /// Panics if `id` is an IO stack.
+    pub fn register_stack(&mut self, id: u64, mut stack: Box<dyn HyeongStack + Send>) {
+        assert!(!self.layout.is_io(id), "IO stacks can't be replaced");
+        if let Some(values) = self.stacks.remove(&id) {
+            stack.push_many(&values);
+        }
+        self.custom.insert(id, stack);
+    }
+
+    /// Sets a hook called with the id of each numbered stack when it's first used, e.g. to track
+    /// how many stacks a program allocates. Stacks which exist already, like the starting one,
+    /// aren't reported, nor are registered stacks and those restored from a snapshot.
+    pub fn on_stack_created<F: FnMut(u64) + Send + 'static>(&mut self, hook: F) {
+        self.stack_created = Some(Box::new(hook));
+    }
+
+    /// Reserves capacity for at least `additional` more values on the numbered stack `id`.
+    ///
+    /// This is only a hint; IO stacks are left untouched.
+    pub fn reserve(&mut self, id: u64, additional: usize) {
+        self.make_stack(id);
+        if let Some(stack) = self.stacks.get_mut(&id) {
+            stack.reserve(additional);
+        }
+    }
+
+    /// Number of values on the numbered stack `id`, or `None` for IO stacks and registered
+    /// stacks.
+    pub fn stack_len(&self, id: u64) -> Option<usize> {
+        if self.layout.is_io(id) || self.custom.contains_key(&id) {
+            None
+        } else {
+            Some(self.stacks.get(&id).map_or(0, Vec::len))
         }
     }
 
+    /// Removes every value from the numbered stack `id`. IO stacks are left untouched.
+    pub fn clear_stack(&mut self, id: u64) {
+        if let Some(stack) = self.stacks.get_mut(&id) {
+            stack.clear();
+        }
+    }
+
+    /// Appends every value of the numbered stack `from` onto stack `to`, bottom first, leaving
+    /// `from` as it is. Values already on `to` stay below the copies.
+    ///
+    /// Copying onto stdout or stderr prints the values, and onto stdin pushes them back. An IO
+    /// stack as `from` has nothing to copy.
+    pub fn clone_stack(&mut self, from: u64, to: u64) {
+        let values = match self.stacks.get(&from) {
+            Some(values) if !self.layout.is_io(from) => values.clone(),
+            _ => return,
+        };
+        if self.has_room(to, values.len() as u64) {
+            self.stack_mut(to).push_many(&values);
+        }
+    }
+
+    /// Number of pushes and operations whose result overflowed the rational backend and became
+    /// NaN.
+    ///
+    /// Only fixed-width backends overflow, so a program with overflows may behave differently
+    /// with the `big-rational` feature.
+    pub fn overflow_count(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Makes an arithmetic operation resulting in NaN end the program with `NAN_EXIT_CODE`, to
+    /// find where NaN first comes from instead of letting it propagate. So does a push too large
+    /// for the rational backend. The result is still pushed first. NaN read from an empty stack
+    /// or past the end of input ends the program as soon as arithmetic is done on it.
+    ///
+    /// `Processor::try_run` reports this as `Error::UnexpectedNan`.
+    pub fn set_fatal_nan(&mut self, fatal: bool) {
+        self.fatal_nan = fatal;
+    }
+
+    /// Whether the program ended because it computed NaN while that is fatal, rather than with
+    /// an exit code which happens to be `NAN_EXIT_CODE`.
+    pub fn stopped_on_nan(&self) -> bool {
+        self.exit_code.is_none() && self.exit_code() == Some(NAN_EXIT_CODE)
+    }
+
+    fn check_result(&mut self, result: &HyeongRational, had_nan: bool) {
+        if result.is_nan() {
+            if !had_nan {
+                self.overflows += 1;
+            }
+            self.nan_computed |= self.fatal_nan;
+        }
+    }
+
+    /// Caps the number of values any numbered stack may hold. A push which would go over it is
+    /// dropped, and the program ends with `DEPTH_LIMIT_EXIT_CODE`. `None` removes the cap.
+    pub fn set_depth_limit(&mut self, max_values: Option<usize>) {
+        self.depth_limit = max_values;
+    }
+
+    /// Makes pushing onto stderr end the program with `STDERR_EXIT_CODE`, for harnesses where
+    /// any error output means failure. The value is still written first.
+    pub fn set_fatal_stderr(&mut self, fatal: bool) {
+        self.fatal_stderr = fatal;
+    }
+
+    /// Whether `count` more values fit on stack `id`. Records going over the depth limit if not,
+    /// and a fatal write to stderr.
+    fn has_room(&mut self, id: u64, count: u64) -> bool {
+        if self.fatal_stderr && id == self.layout.stderr && count > 0 {
+            self.stderr_written = true;
+        }
+        let fits = match (self.depth_limit, self.stack_len(id)) {
+            (Some(limit), Some(len)) => count <= limit.saturating_sub(len) as u64,
+            _ => true,
+        };
+        self.depth_exceeded |= !fits;
+        fits
+    }
+
+    /// The code the program ended with, if it has ended. Failing to write to stdout or stderr
+    /// ends it with `WRITE_ERROR_EXIT_CODE`, going over the output limit of either with
+    /// `OUTPUT_LIMIT_EXIT_CODE`, reaching the EOF limit of stdin with
+    /// `EOF_LIMIT_EXIT_CODE`, going over the depth limit with `DEPTH_LIMIT_EXIT_CODE`, writing
+    /// to stderr while that is fatal with `STDERR_EXIT_CODE`, and computing NaN while that is
+    /// fatal with `NAN_EXIT_CODE`.
     pub fn exit_code(&self) -> Option<isize> {
-        self.exit_code
+        self.exit_code.or_else(|| {
+            if self.stdout.write_failed() || self.stderr.write_failed() {
+                Some(WRITE_ERROR_EXIT_CODE)
+            } else if self.stdout.limit_exceeded() || self.stderr.limit_exceeded() {
+                Some(OUTPUT_LIMIT_EXIT_CODE)
+            } else if self.stdin.eof_limit_reached() {
+                Some(EOF_LIMIT_EXIT_CODE)
+            } else if self.depth_exceeded {
+                Some(DEPTH_LIMIT_EXIT_CODE)
+            } else if self.stderr_written {
+                Some(STDERR_EXIT_CODE)
+            } else if self.nan_computed {
+                Some(NAN_EXIT_CODE)
+            } else {
+                None
+            }
+        })
     }
 
+    /// Ends execution with `code`, the same way selecting stdout or stderr does; the processor
+    /// stops at its next exit check.
+    pub fn set_exit_code(&mut self, code: isize) {
+        self.exit_code = Some(code);
+    }
+
+    /// Pushes `hangul * dots` onto the selected stack.
+    ///
+    /// The product is computed in `u128`, where it can't overflow. If the rational backend can't
+    /// hold it, NaN is pushed instead of a wrapped value, and counted as an overflow.
     pub fn push(&mut self, hangul: u64, dots: u64) {
-        let value = HyeongRational::from_i64((hangul * dots) as i64);
-        self.selected_stack_mut().push_one(value);
+        let value = HyeongRational::from_u128(hangul as u128 * dots as u128);
+        self.check_result(&value, false);
+        self.push_value(value);
+    }
+
+    /// Pushes `value` onto the selected stack as is. Like `push`, this prints it if stdout or
+    /// stderr is selected.
+    pub fn push_value(&mut self, value: HyeongRational) {
+        if self.has_room(self.selected, 1) {
+            self.selected_stack_mut().push_one(value);
+        }
+    }
+
+    /// Pushes `values` onto the selected stack in order, like `push_value` on each of them but
+    /// in one go.
+    pub fn push_values(&mut self, values: &[HyeongRational]) {
+        if self.has_room(self.selected, values.len() as u64) {
+            self.selected_stack_mut().push_many(values);
+        }
+    }
+
+    /// Pops a value off the selected stack. This reads input if stdin is selected and gives NaN
+    /// if stdout or stderr is.
+    pub fn pop(&mut self) -> HyeongRational {
+        self.selected_stack_mut().pop_one()
     }
 
     pub fn add(&mut self, count: u64, to: u64) {
         if self.check_exit() {
             return;
         }
-        let sum = {
+        let (sum, had_nan) = {
             let mut sum = HyeongRational::zero();
+            let mut had_nan = false;
             let stack_from = self.selected_stack_mut();
             for _ in 0..count {
-                sum += stack_from.pop_one();
+                let value = stack_from.pop_one();
+                had_nan |= value.is_nan();
+                sum += value;
             }
-            sum
+            (sum, had_nan)
         };
-        self.stack_mut(to).push_one(sum);
+        self.check_result(&sum, had_nan);
+        if self.has_room(to, 1) {
+            self.stack_mut(to).push_one(sum);
+        }
     }
 
     pub fn mul(&mut self, count: u64, to: u64) {
         if self.check_exit() {
             return;
         }
-        let sum = {
+        let (sum, had_nan) = {
             let mut sum = HyeongRational::one();
+            let mut had_nan = false;
             let stack_from = self.selected_stack_mut();
             for _ in 0..count {
-                sum *= stack_from.pop_one();
+                let value = stack_from.pop_one();
+                had_nan |= value.is_nan();
+                sum *= value;
             }
-            sum
+            (sum, had_nan)
         };
-        self.stack_mut(to).push_one(sum);
+        self.check_result(&sum, had_nan);
+        if self.has_room(to, 1) {
+            self.stack_mut(to).push_one(sum);
+        }
     }
 
     pub fn neg(&mut self, count: u64, to: u64) {
         if self.check_exit() {
             return;
         }
-        let sum = {
+        let (sum, had_nan) = {
             let mut temp = vec![];
+            let mut had_nan = false;
             let stack_from = self.selected_stack_mut();
             for _ in 0..count {
-                temp.push(-stack_from.pop_one());
+                let value = stack_from.pop_one();
+                had_nan |= value.is_nan();
+                temp.push(-value);
             }
 
             let mut it = temp.iter();
             while let Some(r) = it.next_back() {
                 stack_from.push_one(r.clone());
             }
-            temp.into_iter().fold(HyeongRational::zero(), |a, b| a + b)
+            let sum = temp.into_iter().fold(HyeongRational::zero(), |a, b| a + b);
+            (sum, had_nan)
         };
-        self.stack_mut(to).push_one(sum);
+        self.check_result(&sum, had_nan);
+        if self.has_room(to, 1) {
+            self.stack_mut(to).push_one(sum);
+        }
     }
 
     pub fn recip(&mut self, count: u64, to: u64) {
         if self.check_exit() {
             return;
         }
-        let sum = {
+        let (sum, had_nan) = {
             let mut temp = vec![];
+            let mut had_nan = false;
             let stack_from = self.selected_stack_mut();
             for _ in 0..count {
-                temp.push(stack_from.pop_one().recip());
+                let value = stack_from.pop_one();
+                // The reciprocal of zero is NaN by definition, not an overflow
+                had_nan |= value.is_nan() || value.is_zero();
+                temp.push(value.recip());
             }
 
             let mut it = temp.iter();
             while let Some(r) = it.next_back() {
                 stack_from.push_one(r.clone());
             }
-            temp.into_iter().fold(HyeongRational::one(), |a, b| a * b)
+            let product = temp.into_iter().fold(HyeongRational::one(), |a, b| a * b);
+            (product, had_nan)
         };
-        self.stack_mut(to).push_one(sum);
+        self.check_result(&sum, had_nan);
+        if self.has_room(to, 1) {
+            self.stack_mut(to).push_one(sum);
+        }
     }
 
     pub fn dup(&mut self, count: u64, into: u64) {
@@ -231,12 +1121,20 @@ impl<I: Read, O: Write, E: Write> StackManager<I, O, E> {
             value
         };
         self.selected = into;
+        if !self.has_room(into, count) {
+            return;
+        }
         let stack_to = self.selected_stack_mut();
         for _ in 0..count {
             stack_to.push_one(value.clone());
         }
     }
 
+    /// Resolves `heart` against the selected stack.
+    ///
+    /// Every comparison on the way pops a value, even if both of its branches are the same.
+    /// That's why heart trees can't be folded ahead of time: `LessThan(h, h)` still consumes a
+    /// value (or a character of input) before resolving to `h`.
     pub fn process_hearts(&mut self, heart: &HeartTree, target: u64) -> HeartResult {
         match heart {
             HeartTree::Heart(id) => HeartResult::Heart(*id),
@@ -266,10 +1164,13 @@ impl<I: Read, O: Write, E: Write> StackManager<I, O, E> {
     }
 
     fn make_stack(&mut self, id: u64) {
-        match id {
-            0 | 1 | 2 => {}
-            i => {
-                self.stacks.entry(i).or_insert_with(Vec::new);
+        if self.layout.is_io(id) || self.custom.contains_key(&id) {
+            return;
+        }
+        if let Entry::Vacant(entry) = self.stacks.entry(id) {
+            entry.insert(vec![]);
+            if let Some(hook) = &mut self.stack_created {
+                hook(id);
             }
         }
     }
@@ -283,20 +1184,120 @@ impl<I: Read, O: Write, E: Write> StackManager<I, O, E> {
 mod tests {
     mod rw {
         use crate::rational::HyeongRational;
-        use crate::stack::{HyeongReadStack, HyeongStack, HyeongWriteStack};
+        use crate::stack::{
+            CallbackWriter, CountingReader, HyeongReadStack, HyeongStack, HyeongWriteStack,
+            NegativeMode, OutputMode, SharedWriter, StringWriter, WriteStats,
+        };
+        use std::io::{ErrorKind, Read};
+        use std::num::NonZeroUsize;
+
+        #[test]
+        fn write_stack_error() {
+            // Writing to a full slice fails
+            let mut buf = [0; 2];
+            let mut stack = HyeongWriteStack::new(&mut buf[..]);
+            stack.push_one(HyeongRational::from('A'));
+            assert!(!stack.write_failed());
+            stack.push_one(HyeongRational::from('형'));
+            assert!(stack.write_failed());
+            stack.push_one(HyeongRational::from('B'));
+            // Only what was written is counted
+            let stats = stack.stats();
+            assert_eq!((stats.codepoints, stats.bytes), (1, 1));
+            let error = stack.take_error().unwrap();
+            assert_eq!(error.kind(), ErrorKind::WriteZero);
+            assert!(stack.take_error().is_none());
+            assert!(stack.write_failed());
+            assert_eq!(buf[0], b'A');
+        }
 
         #[test]
         fn read_stack_pop() {
             let test_str = "하앗...💕";
             let mut stack = HyeongReadStack::new(test_str.as_bytes());
-            assert_eq!(stack.pop_one(), HyeongRational::from_u64('하' as u32 as u64));
-            assert_eq!(stack.pop_one(), HyeongRational::from_u64('앗' as u32 as u64));
-            assert_eq!(stack.pop_one(), HyeongRational::from_u64('.' as u32 as u64));
+            assert_eq!(stack.pop_one(), HyeongRational::from('하'));
+            assert_eq!(stack.pop_one(), HyeongRational::from('앗'));
+            assert_eq!(stack.pop_one(), HyeongRational::from('.'));
             stack.push_one(HyeongRational::from_u64(14));
             assert_eq!(stack.pop_one(), HyeongRational::from_u64(14));
-            assert_eq!(stack.pop_one(), HyeongRational::from_u64('.' as u32 as u64));
-            assert_eq!(stack.pop_one(), HyeongRational::from_u64('.' as u32 as u64));
-            assert_eq!(stack.pop_one(), HyeongRational::from_u64('💕' as u32 as u64));
+            assert_eq!(stack.pop_one(), HyeongRational::from('.'));
+            assert_eq!(stack.pop_one(), HyeongRational::from('.'));
+            assert_eq!(stack.pop_one(), HyeongRational::from('💕'));
+            assert!(stack.pop_one().is_nan());
+        }
+
+        #[test]
+        fn read_stack_bytes_consumed() {
+            let mut stack = HyeongReadStack::new("A하💕\u{80}".as_bytes());
+            assert_eq!(stack.bytes_consumed(), 0);
+            stack.pop_one();
+            assert_eq!(stack.bytes_consumed(), 1);
+            stack.push_one(HyeongRational::from_u64(14));
+            stack.pop_one();
+            assert_eq!(stack.bytes_consumed(), 1);
+            stack.pop_one();
+            assert_eq!(stack.bytes_consumed(), 4);
+            stack.pop_one();
+            assert_eq!(stack.bytes_consumed(), 8);
+            // U+0080 is two bytes; both are read
+            stack.pop_one();
+            assert_eq!(stack.bytes_consumed(), 10);
+            assert!(stack.pop_one().is_nan());
+            assert_eq!(stack.bytes_consumed(), 10);
+
+            let mut stack = HyeongReadStack::new(&b"\xed\x95"[..]);
+            assert!(stack.pop_one().is_nan());
+            assert_eq!(stack.bytes_consumed(), 2);
+
+            let mut reader = CountingReader::new(&b"abc"[..]);
+            let mut buf = [0; 2];
+            reader.read_exact(&mut buf).unwrap();
+            assert_eq!(reader.count(), 2);
+        }
+
+        #[test]
+        fn read_stack_values_consumed() {
+            let mut stack = HyeongReadStack::new("A하\u{80}".as_bytes());
+            stack.pop_one();
+            stack.push_one(HyeongRational::from_u64(14));
+            stack.pop_one();
+            assert_eq!(stack.values_consumed(), 1);
+            stack.pop_one();
+            stack.pop_one();
+            assert!(stack.pop_one().is_nan());
+            assert_eq!(stack.values_consumed(), 3);
+
+            let mut stack = HyeongReadStack::new_bytes("하".as_bytes());
+            while !stack.pop_one().is_nan() {}
+            assert_eq!(stack.values_consumed(), 3);
+        }
+
+        #[test]
+        fn read_stack_eof_limit() {
+            let mut stack = HyeongReadStack::new("A".as_bytes());
+            stack.set_eof_limit(NonZeroUsize::new(2));
+            assert!(!stack.at_eof());
+            assert_eq!(stack.pop_one(), HyeongRational::from('A'));
+            assert!(stack.pop_one().is_nan());
+            assert!(stack.at_eof());
+            assert!(!stack.eof_limit_reached());
+            // Pushed back values don't count as reads
+            stack.push_one(HyeongRational::from_u64(14));
+            stack.pop_one();
+            assert!(!stack.eof_limit_reached());
+            assert!(stack.pop_one().is_nan());
+            assert!(stack.eof_limit_reached());
+        }
+
+        #[test]
+        fn read_stack_pop_bytes() {
+            let mut stack = HyeongReadStack::new_bytes("A하".as_bytes());
+            assert_eq!(stack.pop_one(), HyeongRational::from('A'));
+            assert_eq!(stack.pop_one(), HyeongRational::from_u64(0xed));
+            assert_eq!(stack.pop_one(), HyeongRational::from_u64(0x95));
+            stack.push_one(HyeongRational::from_u64(14));
+            assert_eq!(stack.pop_one(), HyeongRational::from_u64(14));
+            assert_eq!(stack.pop_one(), HyeongRational::from_u64(0x98));
             assert!(stack.pop_one().is_nan());
         }
 
@@ -305,10 +1306,10 @@ mod tests {
             let mut buf = vec![];
             {
                 let mut stack = HyeongWriteStack::new(&mut buf);
-                stack.push_one(HyeongRational::from_u64('흑' as u32 as u64));
-                stack.push_one(HyeongRational::from_u64('.' as u32 as u64));
-                stack.push_one(HyeongRational::from_u64('.' as u32 as u64));
-                stack.push_one(HyeongRational::from_u64('!' as u32 as u64));
+                stack.push_one(HyeongRational::from('흑'));
+                stack.push_one(HyeongRational::from('.'));
+                stack.push_one(HyeongRational::from('.'));
+                stack.push_one(HyeongRational::from('!'));
                 stack.push_one(HyeongRational::from_i64(-32));
                 stack.push_one(HyeongRational::NaN);
                 stack.push_one(HyeongRational::new_i64(65 * 3 + 2, 3));
@@ -316,10 +1317,201 @@ mod tests {
             };
             assert_eq!(&buf[..], "흑..!32너무 커엇...A2".as_bytes());
         }
+
+        #[test]
+        fn push_many() {
+            let values = [
+                HyeongRational::from('a'),
+                HyeongRational::from('b'),
+                HyeongRational::from('하'),
+            ];
+            let mut vec = vec![HyeongRational::from_u64(1)];
+            vec.push_many(&values);
+            assert_eq!(vec.len(), 4);
+            assert_eq!(vec.pop_one(), HyeongRational::from('하'));
+            let mut read = HyeongReadStack::new("z".as_bytes());
+            read.push_many(&values);
+            assert_eq!(read.pop_one(), HyeongRational::from('하'));
+            let mut write = HyeongWriteStack::with_limit(StringWriter::new(), 4);
+            write.push_many(&values);
+            assert_eq!(write.get_ref().as_str(), "ab");
+            assert!(write.limit_exceeded());
+        }
+
+        #[test]
+        fn write_stack_capture() {
+            let mut stack = HyeongWriteStack::new(StringWriter::new());
+            stack.push_one(HyeongRational::from('a'));
+            assert_eq!(stack.captured(), None);
+            stack.enable_capture();
+            stack.push_one(HyeongRational::from('b'));
+            stack.push_many(&[HyeongRational::from('c'), HyeongRational::from('하')]);
+            assert_eq!(stack.captured(), Some("bc하".as_bytes()));
+            assert_eq!(stack.get_ref().as_str(), "abc하");
+        }
+
+        #[test]
+        fn write_stack_pop() {
+            let mut stack = HyeongWriteStack::new(vec![]);
+            assert!(stack.pop_one().is_nan());
+            assert_eq!(stack.pop_attempts(), 0);
+            stack.set_strict(true);
+            assert!(stack.pop_one().is_nan());
+            assert!(stack.pop_one().is_nan());
+            assert_eq!(stack.pop_attempts(), 2);
+            assert!(stack.get_ref().is_empty());
+        }
+
+        #[test]
+        fn callback_writer() {
+            let mut chunks = vec![];
+            {
+                let writer = CallbackWriter::new(|bytes: &[u8]| chunks.push(bytes.to_vec()));
+                let mut stack = HyeongWriteStack::new(writer);
+                stack.push_one(HyeongRational::from('a'));
+                stack.push_one(HyeongRational::from('하'));
+                stack.push_one(HyeongRational::NaN);
+            }
+            assert_eq!(
+                chunks,
+                vec![
+                    b"a".to_vec(),
+                    "하".as_bytes().to_vec(),
+                    "너무 커엇...".as_bytes().to_vec(),
+                ]
+            );
+        }
+
+        #[test]
+        fn shared_writer() {
+            let writer = SharedWriter::new(vec![]);
+            let mut out = HyeongWriteStack::new(writer.clone());
+            let mut err = HyeongWriteStack::new(writer.clone());
+            out.push_one(HyeongRational::from('a'));
+            err.push_one(HyeongRational::from('b'));
+            out.push_one(HyeongRational::from('c'));
+            let writer = writer.try_into_inner().unwrap_err();
+            drop((out, err));
+            assert_eq!(writer.try_into_inner().unwrap(), b"abc");
+        }
+
+        #[test]
+        fn write_stack_negative_mode() {
+            let values = [
+                HyeongRational::from_i64(-32),
+                HyeongRational::from('A'),
+                HyeongRational::new_i64(-1, 2),
+            ];
+            let expected = [
+                (NegativeMode::Absolute, "32A1"),
+                (NegativeMode::Marker, "너무 커엇...A너무 커엇..."),
+                (NegativeMode::Omit, "A"),
+            ];
+            for &(negative, output) in &expected {
+                let mut stack = HyeongWriteStack::new(StringWriter::new());
+                stack.set_negative_mode(negative);
+                for value in &values {
+                    stack.push_one(value.clone());
+                }
+                assert_eq!(stack.get_ref().as_str(), output);
+            }
+            let mut stack = HyeongWriteStack::with_mode(StringWriter::new(), OutputMode::Rational);
+            stack.set_negative_mode(NegativeMode::Omit);
+            for value in &values {
+                stack.push_one(value.clone());
+            }
+            assert_eq!(stack.get_ref().as_str(), "A-1/2");
+        }
+
+        #[test]
+        fn write_stack_limit() {
+            let mut stack = HyeongWriteStack::with_limit(StringWriter::new(), 4);
+            stack.push_one(HyeongRational::from('a'));
+            assert!(!stack.limit_exceeded());
+            stack.push_one(HyeongRational::from('형'));
+            stack.push_one(HyeongRational::from('형'));
+            assert!(stack.limit_exceeded());
+            stack.push_one(HyeongRational::from('a'));
+            assert_eq!(stack.get_ref().as_str(), "a형");
+            assert_eq!(stack.stats().bytes, 4);
+        }
+
+        #[test]
+        fn char_round_trip() {
+            let mut stack = HyeongWriteStack::new(StringWriter::new());
+            for c in "혀엉...♥💖\u{0}".chars() {
+                stack.push_one(HyeongRational::from(c));
+            }
+            assert_eq!(stack.get_ref().as_str(), "혀엉...♥💖\u{0}");
+        }
+
+        #[test]
+        fn write_stack_string() {
+            let mut stack = HyeongWriteStack::new(StringWriter::new());
+            stack.push_one(HyeongRational::from('흑'));
+            stack.push_one(HyeongRational::from('!'));
+            stack.push_one(HyeongRational::NaN);
+            stack.push_one(HyeongRational::from_i64(-32));
+            assert_eq!(stack.get_ref().as_str(), "흑!너무 커엇...32");
+            assert_eq!(stack.into_inner().into_string(), "흑!너무 커엇...32");
+        }
+
+        #[test]
+        fn write_stack_stats() {
+            let mut stack = HyeongWriteStack::new(vec![]);
+            assert_eq!(stack.stats(), WriteStats::default());
+            stack.push_one(HyeongRational::from('A'));
+            stack.push_one(HyeongRational::from('흑'));
+            stack.push_one(HyeongRational::from_i64(-32));
+            stack.push_one(HyeongRational::NaN);
+            assert_eq!(
+                stack.stats(),
+                WriteStats {
+                    codepoints: 1 + 1 + 2 + 8,
+                    bytes: 1 + 3 + 2 + 16,
+                }
+            );
+            assert_eq!(stack.stats().bytes, stack.get_ref().len());
+        }
+
+        #[test]
+        fn write_stack_rational_mode() {
+            let mut buf = vec![];
+            {
+                let mut stack = HyeongWriteStack::with_mode(&mut buf, OutputMode::Rational);
+                stack.push_one(HyeongRational::from('A'));
+                stack.push_one(HyeongRational::new_i64(65 * 3 + 2, 3));
+                stack.push_one(HyeongRational::new_i64(-11, 7));
+                stack.push_one(HyeongRational::new_i64(-14, 7));
+                stack.push_one(HyeongRational::NaN);
+            };
+            assert_eq!(&buf[..], "A197/3-11/72너무 커엇...".as_bytes());
+        }
+    }
+
+    mod heart {
+        use crate::stack::HeartResult;
+        use crate::structure::HeartTree;
+
+        #[test]
+        fn round_trip() {
+            for &result in &[HeartResult::Heart(3), HeartResult::Return, HeartResult::Nil] {
+                let tree = HeartTree::from(result);
+                assert_eq!(Option::<HeartResult>::from(&tree), Some(result));
+            }
+            let tree = HeartTree::Equals(Box::new(HeartTree::Return), Box::new(HeartTree::Nil));
+            assert_eq!(Option::<HeartResult>::from(&tree), None);
+        }
     }
 
     mod manager {
-        use crate::stack::{HyeongReadStack, HyeongWriteStack, StackManager};
+        use crate::rational::HyeongRational;
+        use crate::stack::{
+            HeartResult, HyeongReadStack, HyeongWriteStack, StackLayout, StackManager,
+            StackSnapshot, DEPTH_LIMIT_EXIT_CODE, NAN_EXIT_CODE, STDERR_EXIT_CODE,
+            WRITE_ERROR_EXIT_CODE,
+        };
+        use crate::structure::{HeartTree, Instruction, OperationType};
 
         macro_rules! extract_arg {
             ($target:ident, [ $t:ident $v:expr ] $($rest:tt)*) => {
@@ -358,6 +1550,334 @@ mod tests {
             }, output "AAA");
         }
 
+        #[test]
+        fn stack_manager_push_overflow() {
+            // Products are exact as long as the backend can hold them
+            #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+            const EXPECTED: &str = "너무 커엇...너무 커엇...4611686016279904256";
+            #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+            const EXPECTED: &str = "3689348814741910323092233720368547758084611686016279904256";
+            make_test!(manager {
+                manager.push(u64::MAX, 2);
+                manager.neg(1, 1);
+                manager.push(1 << 32, 1 << 31);
+                manager.neg(1, 1);
+                manager.push(1 << 31, (1 << 31) - 1);
+                manager.neg(1, 1);
+            }, output EXPECTED);
+        }
+
+        #[test]
+        fn stack_manager_push_value() {
+            make_test!(manager {
+                manager.push_value(HyeongRational::new_i64(131, 2));
+                manager.push_value(HyeongRational::NaN);
+                manager.add(1, 1);
+                manager.add(1, 1);
+            }, output "너무 커엇...A");
+        }
+
+        #[test]
+        fn stack_manager_push_values() {
+            make_test!(manager {
+                manager.push_values(&[HyeongRational::from('b'), HyeongRational::from('a')]);
+                manager.add(1, 1);
+                manager.add(1, 1);
+            }, output "ab");
+        }
+
+        #[test]
+        fn stack_manager_depth_limit() {
+            make_test!(manager {
+                manager.set_depth_limit(Some(3));
+                manager.push(1, 1);
+                manager.dup(2, 3);
+                assert_eq!(manager.exit_code(), None);
+                // Duplicating into the full stack is dropped as a whole
+                manager.dup(1, 3);
+                assert_eq!(manager.stack_len(3), Some(3));
+                assert_eq!(manager.exit_code(), Some(DEPTH_LIMIT_EXIT_CODE));
+            });
+        }
+
+        #[test]
+        fn stack_manager_fatal_stderr() {
+            make_test!(manager {
+                manager.push(5, 13);
+                manager.dup(1, 3);
+                assert_eq!(manager.exit_code(), None);
+                manager.set_fatal_stderr(true);
+                manager.add(1, 2);
+                assert_eq!(manager.exit_code(), Some(STDERR_EXIT_CODE));
+            }, error "A");
+        }
+
+        #[test]
+        fn stack_manager_write_error() {
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let mut buf = [0; 1];
+            let stdout = HyeongWriteStack::new(&mut buf[..]);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let mut manager = StackManager::from_stacks(stdin, stdout, stderr);
+            manager.push(1, 65);
+            manager.push(1, 66);
+            manager.dup(2, 3);
+            manager.add(2, 1);
+            assert_eq!(manager.exit_code(), Some(WRITE_ERROR_EXIT_CODE));
+            assert!(manager.stdout().write_failed());
+            assert!(manager.flush().is_err());
+            assert!(manager.flush().is_ok());
+            assert_eq!(manager.exit_code(), Some(WRITE_ERROR_EXIT_CODE));
+        }
+
+        #[test]
+        fn stack_manager_stack_created() {
+            use std::sync::{Arc, Mutex};
+
+            make_test!(manager {
+                let created = Arc::new(Mutex::new(vec![]));
+                let hook_created = Arc::clone(&created);
+                manager.on_stack_created(move |id| hook_created.lock().unwrap().push(id));
+                manager.push(1, 4);
+                manager.dup(1, 5);
+                manager.dup(1, 3);
+                manager.dup(1, 7);
+                manager.add(1, 1);
+                assert_eq!(*created.lock().unwrap(), vec![5, 7]);
+            }, output "\u{4}");
+        }
+
+        #[test]
+        fn stack_manager_is_send() {
+            // So that a program can run on another thread, hooks and registered stacks included
+            fn assert_send<T: Send>() {}
+            assert_send::<StackManager<&[u8], Vec<u8>, Vec<u8>>>();
+        }
+
+        #[test]
+        fn stack_manager_fatal_nan() {
+            make_test!(manager {
+                manager.set_fatal_nan(true);
+                manager.push(1, 2);
+                manager.push(1, 3);
+                manager.mul(2, 3);
+                manager.recip(1, 3);
+                assert_eq!(manager.exit_code(), None);
+                manager.push(1, 0);
+                manager.recip(1, 3);
+                assert_eq!(manager.exit_code(), Some(NAN_EXIT_CODE));
+            });
+        }
+
+        #[test]
+        fn stack_manager_set_exit_code() {
+            make_test!(manager {
+                assert_eq!(manager.exit_code(), None);
+                manager.set_exit_code(42);
+                assert_eq!(manager.exit_code(), Some(42));
+            });
+        }
+
+        #[test]
+        fn stack_manager_clear_stack() {
+            make_test!(manager {
+                manager.push(5, 13);
+                manager.push(5, 13);
+                manager.clear_stack(3);
+                manager.clear_stack(4);
+                manager.clear_stack(0);
+                manager.dup(1, 1);
+            }, input "A", output "너무 커엇...");
+        }
+
+        #[test]
+        fn stack_manager_in_memory() {
+            let mut manager = StackManager::in_memory("A");
+            manager.dup(1, 0);
+            manager.add(2, 2);
+            manager.push(1, 66);
+            manager.dup(1, 1);
+            assert_eq!(manager.stdout().get_ref().as_str(), "B");
+            assert_eq!(manager.stderr().get_ref().as_str(), "너무 커엇...");
+            let (_, stdout, stderr) = manager.into_io();
+            assert_eq!(stdout.into_inner().into_string(), "B");
+            assert_eq!(stderr.into_inner().into_string(), "너무 커엇...");
+        }
+
+        #[test]
+        fn stack_manager_register_stack() {
+            use crate::stack::HyeongStack;
+
+            // Pops the largest value first
+            struct MaxHeap(Vec<HyeongRational>);
+            impl HyeongStack for MaxHeap {
+                fn push_one(&mut self, value: HyeongRational) {
+                    let at = self.0.partition_point(|v| v < &value);
+                    self.0.insert(at, value);
+                }
+                fn pop_one(&mut self) -> HyeongRational {
+                    self.0.pop().into()
+                }
+            }
+
+            make_test!(manager {
+                manager.push(1, 66);
+                manager.register_stack(3, Box::new(MaxHeap(vec![])));
+                assert_eq!(manager.stack_len(3), None);
+                manager.push(1, 67);
+                manager.push(1, 65);
+                manager.add(1, 1);
+                manager.add(1, 1);
+                manager.add(1, 1);
+            }, output "CBA");
+        }
+
+        #[test]
+        fn stack_manager_clone_stack() {
+            make_test!(manager {
+                manager.push(5, 13);
+                manager.push(1, 66);
+                manager.dup(1, 4);
+                manager.clone_stack(3, 4);
+                manager.clone_stack(3, 3);
+                assert_eq!(manager.stack_len(3), Some(4));
+                assert_eq!(manager.stack_len(4), Some(3));
+                // The copies go on top of what was already there
+                manager.clone_stack(4, 1);
+                manager.clone_stack(1, 3);
+                manager.clone_stack(5, 3);
+                assert_eq!(manager.stack_len(3), Some(4));
+            }, output "BAB");
+        }
+
+        #[test]
+        fn stack_manager_identical_branches() {
+            make_test!(manager {
+                let heart = HeartTree::LessThan(Box::new(HeartTree::Heart(0)), Box::new(HeartTree::Heart(0)));
+                manager.push(1, 1);
+                manager.push(1, 2);
+                assert_eq!(manager.process_hearts(&heart, 1), HeartResult::Heart(0));
+                manager.dup(1, 1);
+            }, output "\u{1}");
+        }
+
+        #[test]
+        fn stack_manager_default_layout() {
+            make_test!(manager {
+                let layout = manager.layout();
+                assert_eq!(layout, StackLayout::default());
+                assert_eq!((layout.stdin, layout.stdout, layout.stderr), (0, 1, 2));
+                assert_eq!(manager.snapshot().selected, 3);
+                manager.dup(1, 2);
+                manager.add(1, 3);
+                assert_eq!(manager.exit_code(), Some(1));
+            });
+        }
+
+        #[test]
+        fn stack_manager_custom_layout() {
+            let mut output = vec![];
+            let mut error = vec![];
+            {
+                let layout = StackLayout {
+                    stdin: 10,
+                    stdout: 11,
+                    stderr: 12,
+                    start: 0,
+                    stdout_exit_code: 7,
+                    stderr_exit_code: 8,
+                };
+                let stdin = HyeongReadStack::new("B".as_bytes());
+                let stdout = HyeongWriteStack::new(&mut output);
+                let stderr = HyeongWriteStack::new(&mut error);
+                let mut manager = StackManager::with_layout(stdin, stdout, stderr, layout);
+                // Stack 0 is a numbered stack now
+                manager.push(5, 13);
+                manager.add(1, 11);
+                manager.dup(0, 10);
+                manager.add(1, 12);
+                manager.dup(0, 11);
+                manager.add(1, 1);
+                assert_eq!(manager.exit_code(), Some(7));
+            }
+            assert_eq!(&output[..], b"A");
+            assert_eq!(&error[..], b"B");
+        }
+
+        #[test]
+        #[should_panic]
+        fn stack_manager_overlapping_layout() {
+            let layout = StackLayout {
+                stderr: 1,
+                ..StackLayout::default()
+            };
+            let stdin = HyeongReadStack::new(&b""[..]);
+            StackManager::with_layout(
+                stdin,
+                HyeongWriteStack::new(vec![]),
+                HyeongWriteStack::new(vec![]),
+                layout,
+            );
+        }
+
+        #[test]
+        fn stack_manager_debug() {
+            make_test!(manager {
+                manager.push(2, 3);
+                manager.recip(1, 4);
+                manager.push_value(HyeongRational::NaN);
+                manager.push_value(HyeongRational::new_i64(-7, 2));
+                assert_eq!(
+                    format!("{:?}", manager),
+                    "StackManager { selected: 3, stacks: {3: [1/6, NaN, -7/2], 4: [1/6]}, \
+                     input: [], exit_code: None }"
+                );
+            });
+        }
+
+        #[test]
+        fn stack_manager_snapshot() {
+            make_test!(manager {
+                let initial = manager.snapshot();
+                manager.push(2, 3);
+                manager.recip(1, 4);
+                manager.dup(1, 5);
+                manager.push(1, 0);
+                manager.recip(1, 3);
+                let expected = StackSnapshot {
+                    selected: 5,
+                    stacks: [
+                        (3, vec![HyeongRational::new_i64(1, 6), HyeongRational::NaN]),
+                        (4, vec![HyeongRational::new_i64(1, 6)]),
+                        (5, vec![HyeongRational::new_i64(1, 6), HyeongRational::NaN]),
+                    ]
+                    .into_iter()
+                    .collect(),
+                    input: vec![],
+                };
+                let snapshot = manager.snapshot();
+                assert_eq!(snapshot, expected);
+                assert_eq!(snapshot.clone(), snapshot);
+                assert_ne!(snapshot, initial);
+
+                manager.restore(&initial);
+                assert_eq!(manager.snapshot(), initial);
+                manager.restore(&snapshot);
+                assert_eq!(manager.snapshot(), expected);
+                manager.dup(1, 1);
+            }, output "너무 커엇...");
+        }
+
+        #[test]
+        fn stack_manager_reserve() {
+            make_test!(manager {
+                manager.reserve(3, 64);
+                manager.reserve(1, 64);
+                manager.push(5, 13);
+                manager.dup(1, 1);
+            }, output "A");
+        }
+
         #[test]
         fn stack_manager_add_mul() {
             make_test!(manager {
@@ -391,5 +1911,75 @@ mod tests {
                 manager.add(1, 1);
             }, input "밯망희", output "481754758155148", error "2너무 커엇...");
         }
+
+        #[test]
+        fn stack_manager_overflow_count() {
+            make_test!(manager {
+                // The reciprocal of zero and operations on NaN aren't overflows
+                manager.push(1, 0);
+                manager.recip(1, 4);
+                manager.add(2, 4);
+                assert_eq!(manager.overflow_count(), 0);
+                manager.push_value(HyeongRational::from_i64(i64::MAX));
+                manager.dup(1, 3);
+                manager.add(2, 4);
+                #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+                assert_eq!(manager.overflow_count(), 1);
+                #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+                assert_eq!(manager.overflow_count(), 0);
+            });
+
+            // A push of more than `i64::MAX`, as the processor does it
+            let push = Instruction::build(OperationType::Push, 1 << 32, 1 << 31, HeartTree::Nil);
+            assert!(push.is_well_formed());
+            make_test!(manager {
+                manager.push(push.hangul_count(), push.dots());
+                let snapshot = manager.snapshot();
+                let top = snapshot.stacks[&3].last().unwrap();
+                #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+                {
+                    assert!(top.is_nan());
+                    assert_eq!(manager.overflow_count(), 1);
+                }
+                #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+                {
+                    assert_eq!(*top, HyeongRational::from_u128(1 << 63));
+                    assert_eq!(manager.overflow_count(), 0);
+                }
+            });
+        }
+
+        #[test]
+        fn stack_manager_recip_overflow() {
+            use crate::parser::Parser;
+            use crate::processor::Processor;
+
+            // Builds -2^63, then takes its reciprocal
+            let source = format!(
+                "혀{}엉{} 흑... 하앗... 흑... 흐읏.... 흑.... 흐읍.....",
+                "어".repeat(65534),
+                ".".repeat(32768)
+            );
+            let stdin = HyeongReadStack::new(&b""[..]);
+            let stdout = HyeongWriteStack::new(vec![]);
+            let stderr = HyeongWriteStack::new(vec![]);
+            let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+            let mut processor = Processor::with_stack_manager(Parser::new(&source), stacks);
+            processor.step_n(7);
+            let overflows = processor.stack_manager().overflow_count();
+            #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+            assert_eq!(overflows, 1);
+            #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+            assert_eq!(overflows, 0);
+
+            make_test!(manager {
+                manager.push_value(HyeongRational::from_i64(i64::MIN));
+                manager.recip(1, 4);
+                #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+                assert_eq!(manager.overflow_count(), 1);
+                #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+                assert_eq!(manager.overflow_count(), 0);
+            });
+        }
     }
 }
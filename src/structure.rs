@@ -1,8 +1,46 @@
-#[derive(Debug, PartialEq)]
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+use super::error::Error;
+use super::parser::HEART_MARKS;
+use super::rational::HyeongRational;
+
+/// A position in source code. Both are 1-based, and columns count chars.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LineColumn {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Where an instruction is in its source: from the start of its hangul to the end of the last
+/// token attached to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    pub bytes: Range<usize>,
+    pub chars: Range<usize>,
+    pub start: LineColumn,
+    /// The position just past the end, like the end of the ranges.
+    pub end: LineColumn,
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instruction {
     op: Operation,
     dots: u64,
     hearts: HeartTree,
+    #[cfg_attr(feature = "serde", serde(default))]
+    span: Option<Span>,
+}
+
+/// Instructions are equal if they do the same thing, wherever they came from; spans aren't
+/// compared.
+impl PartialEq for Instruction {
+    fn eq(&self, other: &Self) -> bool {
+        self.op == other.op && self.dots == other.dots && self.hearts == other.hearts
+    }
 }
 
 impl Instruction {
@@ -11,9 +49,18 @@ impl Instruction {
             op,
             dots,
             hearts,
+            span: None,
         }
     }
 
+    /// Builds an instruction from its semantic parts, without spelling out its hangul.
+    ///
+    /// Like hangul that don't parse, a `hangul_count` of zero can't be written down; see
+    /// `to_source`.
+    pub fn build(op_type: OperationType, hangul_count: u64, dots: u64, hearts: HeartTree) -> Self {
+        Self::new(Operation::new(op_type, hangul_count), dots, hearts)
+    }
+
     pub fn operation_type(&self) -> OperationType {
         self.op.op_type
     }
@@ -26,22 +73,81 @@ impl Instruction {
         self.dots
     }
 
+    /// The product of the hangul and dot counts, which is what the instruction pushes and the
+    /// parameter its hearts jump with. Saturates for instructions which aren't well-formed.
     pub fn hangul_times_dots(&self) -> u64 {
-        self.op.hangul_count * self.dots
+        self.op.hangul_count.saturating_mul(self.dots)
+    }
+
+    /// Whether the instruction could have been parsed: it has at least one hangul, and its
+    /// hangul times dots fits in 64 bits. Instructions built or decoded from untrusted input
+    /// may not be.
+    pub fn is_well_formed(&self) -> bool {
+        self.op.hangul_count > 0 && self.op.hangul_count.checked_mul(self.dots).is_some()
     }
 
     pub fn heart_tree(&self) -> &HeartTree {
         &self.hearts
     }
+
+    /// Where the instruction was parsed from. Instructions not parsed from source have none.
+    pub fn span(&self) -> Option<&Span> {
+        self.span.as_ref()
+    }
+
+    pub fn with_span(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    /// The value this instruction pushes if it is a push, computed like `StackManager::push`
+    /// does: NaN if the rational backend can't hold it.
+    pub fn push_value(&self) -> Option<HyeongRational> {
+        (self.op.op_type == OperationType::Push)
+            .then(|| HyeongRational::from_u128(self.op.hangul_count as u128 * self.dots as u128))
+    }
+
+    /// Spells the instruction in a canonical form which parses back to it: the shortest hangul
+    /// for its operation, `.` for each dot, then its hearts.
+    ///
+    /// Returns `None` if the instruction can't be written down, which is never the case for
+    /// parsed ones.
+    pub fn to_source(&self) -> Option<String> {
+        let (single, start, filler, end) = match self.op.op_type {
+            OperationType::Push => ('형', '혀', '어', '엉'),
+            OperationType::Add => ('항', '하', '아', '앙'),
+            OperationType::Multiply => ('핫', '하', '아', '앗'),
+            OperationType::Negate => ('흣', '흐', '으', '읏'),
+            OperationType::Reciprocate => ('흡', '흐', '으', '읍'),
+            OperationType::Duplicate => ('흑', '흐', '으', '윽'),
+        };
+        let mut source = String::new();
+        match self.op.hangul_count {
+            0 => return None,
+            1 => source.push(single),
+            count => {
+                source.push(start);
+                for _ in 2..count {
+                    source.push(filler);
+                }
+                source.push(end);
+            }
+        }
+        source.push_str(&".".repeat(usize::try_from(self.dots).ok()?));
+        self.hearts.write_less_than(&mut source)?;
+        Some(source)
+    }
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Operation {
     op_type: OperationType,
     hangul_count: u64,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum OperationType {
     Push,        // 형
     Add,         // 항
@@ -51,43 +157,58 @@ pub enum OperationType {
     Duplicate,   // 흑
 }
 
+impl OperationType {
+    /// Every operation type, in declaration order; `op as usize` indexes into it.
+    pub const ALL: [OperationType; 6] = [
+        OperationType::Push,
+        OperationType::Add,
+        OperationType::Multiply,
+        OperationType::Negate,
+        OperationType::Reciprocate,
+        OperationType::Duplicate,
+    ];
+}
+
 impl Operation {
+    pub fn new(op_type: OperationType, hangul_count: u64) -> Self {
+        Operation {
+            op_type,
+            hangul_count,
+        }
+    }
+
+    pub fn op_type(&self) -> OperationType {
+        self.op_type
+    }
+
+    pub fn hangul_count(&self) -> u64 {
+        self.hangul_count
+    }
+
+    /// The operation spelled by the hangul `start`, the hangul `end` closing it if it isn't
+    /// self-ending, and `count` hangul in total. Self-ending operations are always one hangul.
+    pub fn try_from_chars(start: char, end: Option<char>, count: u64) -> Result<Self, Error> {
+        let op_type = match (start, end) {
+            ('형', None) | ('혀', Some('엉')) => OperationType::Push,
+            ('항', None) | ('하', Some('앙')) => OperationType::Add,
+            ('핫', None) | ('하', Some('앗')) => OperationType::Multiply,
+            ('흣', None) | ('흐', Some('읏')) => OperationType::Negate,
+            ('흡', None) | ('흐', Some('읍')) => OperationType::Reciprocate,
+            ('흑', None) | ('흐', Some('윽')) => OperationType::Duplicate,
+            _ => return Err(Error::InvalidOperation { start, end }),
+        };
+        let hangul_count = if end.is_some() { count } else { 1 };
+        Ok(Operation {
+            op_type,
+            hangul_count,
+        })
+    }
+
+    /// Like `try_from_chars`, but panics if the characters don't make an operation.
     pub fn from_chars(start: char, end: Option<char>, count: u64) -> Self {
-        if let Some(c) = end {
-            assert!(
-                match start {
-                    '혀' => '엉' == end.unwrap(),
-                    '하' => "앙앗".contains(end.unwrap()),
-                    '흐' => "읏읍윽".contains(end.unwrap()),
-                    _ => false,
-                },
-                "Invalid start-end character pair"
-            );
-            Operation {
-                op_type: match c {
-                    '엉' => OperationType::Push,
-                    '앙' => OperationType::Add,
-                    '앗' => OperationType::Multiply,
-                    '읏' => OperationType::Negate,
-                    '읍' => OperationType::Reciprocate,
-                    '윽' => OperationType::Duplicate,
-                    _ => unreachable!(),
-                },
-                hangul_count: count,
-            }
-        } else {
-            Operation {
-                op_type: match start {
-                    '형' => OperationType::Push,
-                    '항' => OperationType::Add,
-                    '핫' => OperationType::Multiply,
-                    '흣' => OperationType::Negate,
-                    '흡' => OperationType::Reciprocate,
-                    '흑' => OperationType::Duplicate,
-                    _ => panic!("Non-self-ending character without end character"),
-                },
-                hangul_count: 1,
-            }
+        match Self::try_from_chars(start, end, count) {
+            Ok(op) => op,
+            Err(e) => panic!("{}", e),
         }
     }
 
@@ -108,7 +229,8 @@ impl Operation {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum HeartTree {
     Heart(u64),
     Return,
@@ -116,3 +238,458 @@ pub enum HeartTree {
     Equals(Box<HeartTree>, Box<HeartTree>),
     Nil,
 }
+
+impl HeartTree {
+    // `?` separates `LessThan` operands and binds looser than `!`; both group to the right
+    fn write_less_than(&self, source: &mut String) -> Option<()> {
+        match self {
+            HeartTree::LessThan(l, r) => {
+                l.write_equals(source)?;
+                source.push('?');
+                r.write_less_than(source)
+            }
+            _ => self.write_equals(source),
+        }
+    }
+
+    fn write_equals(&self, source: &mut String) -> Option<()> {
+        match self {
+            HeartTree::Equals(l, r) => {
+                l.write_leaf(source)?;
+                source.push('!');
+                r.write_equals(source)
+            }
+            _ => self.write_leaf(source),
+        }
+    }
+
+    fn write_leaf(&self, source: &mut String) -> Option<()> {
+        match self {
+            HeartTree::Heart(id) => source.push(*HEART_MARKS.get(usize::try_from(*id).ok()?)?),
+            HeartTree::Return => source.push('\u{2661}'),
+            HeartTree::Nil => {}
+            HeartTree::LessThan(..) | HeartTree::Equals(..) => return None,
+        }
+        Some(())
+    }
+
+    /// Number of `Heart` and `Return` leaves.
+    pub fn heart_count(&self) -> usize {
+        match self {
+            HeartTree::Heart(_) | HeartTree::Return => 1,
+            HeartTree::Nil => 0,
+            HeartTree::LessThan(l, r) | HeartTree::Equals(l, r) => {
+                l.heart_count() + r.heart_count()
+            }
+        }
+    }
+
+    /// Number of comparisons on the longest path from the root; a leaf has depth 0.
+    pub fn depth(&self) -> usize {
+        match self {
+            HeartTree::Heart(_) | HeartTree::Return | HeartTree::Nil => 0,
+            HeartTree::LessThan(l, r) | HeartTree::Equals(l, r) => 1 + l.depth().max(r.depth()),
+        }
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, HeartTree::Nil)
+    }
+}
+
+/// An error decoding bytecode produced by `encode_bytecode`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BytecodeError {
+    /// The bytecode ended in the middle of an instruction.
+    UnexpectedEnd,
+    InvalidOpcode(u8),
+    InvalidHeartTag(u8),
+    /// A number didn't fit in 64 bits.
+    Overflow,
+    /// An instruction wasn't well-formed; see `Instruction::is_well_formed`.
+    InvalidCounts {
+        hangul_count: u64,
+        dots: u64,
+    },
+    /// A heart tree was nested deeper than `MAX_HEART_DEPTH`.
+    TooDeep,
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::UnexpectedEnd => write!(f, "unexpected end of bytecode"),
+            BytecodeError::InvalidOpcode(b) => write!(f, "invalid opcode {:#04x}", b),
+            BytecodeError::InvalidHeartTag(b) => write!(f, "invalid heart tag {:#04x}", b),
+            BytecodeError::Overflow => write!(f, "number too large"),
+            BytecodeError::InvalidCounts { hangul_count, dots } => write!(
+                f,
+                "invalid instruction with {} hangul and {} dots",
+                hangul_count, dots
+            ),
+            BytecodeError::TooDeep => write!(f, "heart tree nested too deeply"),
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+const TAG_NIL: u8 = 0;
+const TAG_RETURN: u8 = 1;
+const TAG_HEART: u8 = 2;
+const TAG_LESS_THAN: u8 = 3;
+const TAG_EQUALS: u8 = 4;
+
+/// The deepest heart tree `decode_bytecode` accepts, counted as by `HeartTree::depth`.
+///
+/// Dropping, cloning or comparing a heart tree recurses once per level, so a deeper tree from
+/// untrusted bytecode could overflow the call stack. Source needs over a thousand `?` after a
+/// single instruction to go past this.
+pub const MAX_HEART_DEPTH: usize = 1024;
+
+/// Serializes instructions into a compact binary form.
+///
+/// Each instruction is its opcode byte, then `hangul_count` and `dots` as LEB128 varints, then
+/// the heart tree in prefix order as tag bytes, with heart ids as varints.
+pub fn encode_bytecode(instructions: &[Instruction]) -> Vec<u8> {
+    let mut buf = vec![];
+    for instr in instructions {
+        buf.push(instr.op.op_type as u8);
+        write_varint(&mut buf, instr.op.hangul_count);
+        write_varint(&mut buf, instr.dots);
+        write_heart_tree(&mut buf, &instr.hearts);
+    }
+    buf
+}
+
+/// Deserializes instructions serialized by `encode_bytecode`.
+///
+/// Heart trees deeper than `MAX_HEART_DEPTH` are rejected with `BytecodeError::TooDeep`.
+pub fn decode_bytecode(mut bytes: &[u8]) -> Result<Vec<Instruction>, BytecodeError> {
+    let mut instructions = vec![];
+    while let Some((&opcode, rest)) = bytes.split_first() {
+        bytes = rest;
+        let op_type = *OperationType::ALL
+            .get(opcode as usize)
+            .ok_or(BytecodeError::InvalidOpcode(opcode))?;
+        let hangul_count = read_varint(&mut bytes)?;
+        let dots = read_varint(&mut bytes)?;
+        let hearts = read_heart_tree(&mut bytes)?;
+        let instr = Instruction::build(op_type, hangul_count, dots, hearts);
+        if !instr.is_well_formed() {
+            return Err(BytecodeError::InvalidCounts { hangul_count, dots });
+        }
+        instructions.push(instr);
+    }
+    Ok(instructions)
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u64, BytecodeError> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let (&b, rest) = bytes.split_first().ok_or(BytecodeError::UnexpectedEnd)?;
+        *bytes = rest;
+        let low = (b & 0x7f) as u64;
+        if shift >= 64 || (shift == 63 && low > 1) {
+            return Err(BytecodeError::Overflow);
+        }
+        value |= low << shift;
+        if b & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn write_heart_tree(buf: &mut Vec<u8>, tree: &HeartTree) {
+    match tree {
+        HeartTree::Nil => buf.push(TAG_NIL),
+        HeartTree::Return => buf.push(TAG_RETURN),
+        HeartTree::Heart(id) => {
+            buf.push(TAG_HEART);
+            write_varint(buf, *id);
+        }
+        HeartTree::LessThan(l, r) => {
+            buf.push(TAG_LESS_THAN);
+            write_heart_tree(buf, l);
+            write_heart_tree(buf, r);
+        }
+        HeartTree::Equals(l, r) => {
+            buf.push(TAG_EQUALS);
+            write_heart_tree(buf, l);
+            write_heart_tree(buf, r);
+        }
+    }
+}
+
+// Iterative, and bounded by `MAX_HEART_DEPTH`, so that untrusted input can't overflow the call
+// stack here nor when the tree is used
+fn read_heart_tree(bytes: &mut &[u8]) -> Result<HeartTree, BytecodeError> {
+    // Branches waiting for their children: (tag, left child once decoded)
+    let mut pending: Vec<(u8, Option<HeartTree>)> = vec![];
+    loop {
+        let (&tag, rest) = bytes.split_first().ok_or(BytecodeError::UnexpectedEnd)?;
+        *bytes = rest;
+        let mut node = match tag {
+            TAG_NIL => HeartTree::Nil,
+            TAG_RETURN => HeartTree::Return,
+            TAG_HEART => HeartTree::Heart(read_varint(bytes)?),
+            TAG_LESS_THAN | TAG_EQUALS => {
+                if pending.len() >= MAX_HEART_DEPTH {
+                    return Err(BytecodeError::TooDeep);
+                }
+                pending.push((tag, None));
+                continue;
+            }
+            tag => return Err(BytecodeError::InvalidHeartTag(tag)),
+        };
+        loop {
+            match pending.pop() {
+                None => return Ok(node),
+                Some((tag, None)) => {
+                    pending.push((tag, Some(node)));
+                    break;
+                }
+                Some((tag, Some(lhs))) => {
+                    let (lhs, rhs) = (Box::new(lhs), Box::new(node));
+                    node = if tag == TAG_LESS_THAN {
+                        HeartTree::LessThan(lhs, rhs)
+                    } else {
+                        HeartTree::Equals(lhs, rhs)
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::error::Error;
+    use super::super::parser::Parser;
+    use super::super::rational::HyeongRational;
+    use super::{
+        decode_bytecode, encode_bytecode, BytecodeError, HeartTree, Instruction, Operation,
+        OperationType, MAX_HEART_DEPTH, TAG_HEART, TAG_LESS_THAN,
+    };
+
+    #[test]
+    fn push_value() {
+        let instructions = Parser::new("혀어엉..... 형 항...").collect::<Vec<_>>();
+        assert_eq!(
+            instructions[0].push_value(),
+            Some(HyeongRational::from_u64(15))
+        );
+        assert_eq!(
+            instructions[1].push_value(),
+            Some(HyeongRational::from_u64(0))
+        );
+        assert!(instructions[2].push_value().is_none());
+        let huge = Instruction::new(
+            Operation::from_chars('형', None, 1),
+            u64::MAX,
+            HeartTree::Nil,
+        );
+        #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+        assert!(huge.push_value().unwrap().is_nan());
+        #[cfg(any(feature = "big-rational", feature = "rational-i128"))]
+        assert_eq!(huge.push_value(), Some(HyeongRational::from_u64(u64::MAX)));
+    }
+
+    #[test]
+    fn build() {
+        let hearts =
+            HeartTree::LessThan(Box::new(HeartTree::Heart(3)), Box::new(HeartTree::Return));
+        let instr = Instruction::build(OperationType::Multiply, 3, 2, hearts);
+        assert_eq!(instr.to_source().unwrap(), "하아앗..💖?♡");
+        assert_eq!(Parser::new("하아앗..💖?♡").next(), Some(instr));
+        let op = Operation::new(OperationType::Duplicate, 2);
+        assert_eq!(op, Operation::from_chars('흐', Some('윽'), 2));
+        assert_eq!(op.op_type(), OperationType::Duplicate);
+        assert_eq!(op.hangul_count(), 2);
+    }
+
+    #[test]
+    fn try_from_chars() {
+        let op = Operation::try_from_chars('하', Some('앗'), 3).unwrap();
+        assert_eq!(op, Operation::new(OperationType::Multiply, 3));
+        let op = Operation::try_from_chars('흡', None, 5).unwrap();
+        assert_eq!(op, Operation::new(OperationType::Reciprocate, 1));
+        for (start, end) in [
+            ('혀', Some('읏')),
+            ('하', None),
+            ('흑', Some('윽')),
+            ('가', None),
+        ] {
+            match Operation::try_from_chars(start, end, 2) {
+                Err(Error::InvalidOperation { start: s, end: e }) => {
+                    assert_eq!((s, e), (start, end))
+                }
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn to_source_round_trip() {
+        let sources = [
+            include_str!("../snippets/hello-world.hyeong"),
+            include_str!("../snippets/fibonacci.hyeong"),
+            include_str!("../snippets/stderr.hyeong"),
+            "혀어어엉… 하아앗.⋮ 흐으으윽...♥!♡?💖!!?♥? 흡.♡♥!💝 하아앙? 흣!♥ 핫",
+        ];
+        for source in &sources {
+            let instructions = Parser::new(source).collect::<Vec<_>>();
+            let formatted = instructions
+                .iter()
+                .map(|instr| instr.to_source().unwrap() + "\n")
+                .collect::<String>();
+            assert_eq!(Parser::new(&formatted).collect::<Vec<_>>(), instructions);
+        }
+        let instr = Parser::new("하아앗…♥!♡?💖 흐읏").next().unwrap();
+        assert_eq!(instr.to_source().unwrap(), "하아앗...♥!♡?💖");
+    }
+
+    #[test]
+    fn to_source_unrepresentable() {
+        let op = Operation::from_single_char('형', 0);
+        assert_eq!(Instruction::new(op, 1, HeartTree::Nil).to_source(), None);
+        let op = Operation::from_single_char('형', 1);
+        let heart = HeartTree::Heart(11);
+        assert_eq!(Instruction::new(op, 1, heart).to_source(), None);
+        let nested = HeartTree::Equals(
+            Box::new(HeartTree::LessThan(
+                Box::new(HeartTree::Nil),
+                Box::new(HeartTree::Nil),
+            )),
+            Box::new(HeartTree::Nil),
+        );
+        assert_eq!(Instruction::new(op, 1, nested).to_source(), None);
+    }
+
+    #[test]
+    fn heart_tree_queries() {
+        let nil = HeartTree::Nil;
+        assert!(nil.is_nil());
+        assert_eq!(nil.heart_count(), 0);
+        assert_eq!(nil.depth(), 0);
+
+        let heart = HeartTree::Heart(3);
+        assert!(!heart.is_nil());
+        assert_eq!(heart.heart_count(), 1);
+        assert_eq!(heart.depth(), 0);
+
+        // less[_][eq[♥][eq[♡][_]]]
+        let tree = HeartTree::LessThan(
+            Box::new(HeartTree::Nil),
+            Box::new(HeartTree::Equals(
+                Box::new(HeartTree::Heart(0)),
+                Box::new(HeartTree::Equals(
+                    Box::new(HeartTree::Return),
+                    Box::new(HeartTree::Nil),
+                )),
+            )),
+        );
+        assert!(!tree.is_nil());
+        assert_eq!(tree.heart_count(), 2);
+        assert_eq!(tree.depth(), 3);
+    }
+
+    #[test]
+    fn bytecode_round_trip() {
+        for source in &[
+            include_str!("../snippets/hello-world.hyeong"),
+            include_str!("../snippets/fibonacci.hyeong"),
+            "하아앗...! ♥ ? 💖 흐읏...!♡! 혀어어어어어어어어어어어어어어어어엉.....",
+        ] {
+            let instructions = Parser::new(source).collect::<Vec<_>>();
+            let bytecode = encode_bytecode(&instructions);
+            assert_eq!(decode_bytecode(&bytecode), Ok(instructions));
+        }
+    }
+
+    #[test]
+    fn bytecode_layout() {
+        let instr = Instruction::new(
+            Operation::from_single_char('핫', 3),
+            200,
+            HeartTree::LessThan(Box::new(HeartTree::Nil), Box::new(HeartTree::Heart(1))),
+        );
+        let bytecode = encode_bytecode(&[instr]);
+        assert_eq!(bytecode, [2, 3, 0xc8, 0x01, 3, 0, 2, 1]);
+    }
+
+    #[test]
+    fn bytecode_errors() {
+        assert_eq!(decode_bytecode(&[]), Ok(vec![]));
+        assert_eq!(decode_bytecode(&[6]), Err(BytecodeError::InvalidOpcode(6)));
+        assert_eq!(decode_bytecode(&[0, 1]), Err(BytecodeError::UnexpectedEnd));
+        assert_eq!(
+            decode_bytecode(&[0, 1, 1, 5]),
+            Err(BytecodeError::InvalidHeartTag(5))
+        );
+        assert_eq!(
+            decode_bytecode(&[0, 1, 1, 3, 0]),
+            Err(BytecodeError::UnexpectedEnd)
+        );
+        let mut overflow = vec![0];
+        overflow.extend_from_slice(&[0xff; 10]);
+        overflow.push(0x01);
+        assert_eq!(decode_bytecode(&overflow), Err(BytecodeError::Overflow));
+        let huge = Instruction::build(OperationType::Push, 1 << 32, 1 << 32, HeartTree::Nil);
+        assert!(!huge.is_well_formed());
+        assert_eq!(huge.hangul_times_dots(), u64::MAX);
+        assert_eq!(
+            decode_bytecode(&encode_bytecode(&[huge])),
+            Err(BytecodeError::InvalidCounts {
+                hangul_count: 1 << 32,
+                dots: 1 << 32
+            })
+        );
+        let empty = Instruction::build(OperationType::Add, 0, 1, HeartTree::Nil);
+        assert_eq!(
+            decode_bytecode(&encode_bytecode(&[empty])),
+            Err(BytecodeError::InvalidCounts {
+                hangul_count: 0,
+                dots: 1
+            })
+        );
+    }
+
+    #[test]
+    fn bytecode_depth_limit() {
+        // A push whose heart tree nests `depth` levels to the left, each with a heart on the right
+        let nested = |depth: usize| {
+            let mut bytecode = vec![0, 1, 1];
+            bytecode.resize(bytecode.len() + depth, TAG_LESS_THAN);
+            for _ in 0..=depth {
+                bytecode.extend_from_slice(&[TAG_HEART, 0]);
+            }
+            bytecode
+        };
+
+        let decoded = decode_bytecode(&nested(MAX_HEART_DEPTH)).unwrap();
+        assert_eq!(decoded[0].hearts.depth(), MAX_HEART_DEPTH);
+        assert_eq!(decoded.clone(), decoded);
+        drop(decoded);
+
+        assert_eq!(
+            decode_bytecode(&nested(MAX_HEART_DEPTH + 1)),
+            Err(BytecodeError::TooDeep)
+        );
+        // Deep enough to overflow the stack when dropped, were it accepted
+        assert_eq!(
+            decode_bytecode(&nested(1_000_000)),
+            Err(BytecodeError::TooDeep)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+//! Helpers for building test suites of Hyeong programs on top of this interpreter.
+
+use super::parser::Parser;
+use super::processor::Processor;
+use super::stack::{HyeongReadStack, HyeongWriteStack, StackManager};
+
+/// Everything a program run produced.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnippetResult {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: isize,
+}
+
+/// Runs `source` to the end with `stdin` as input, capturing its output.
+///
+/// Like any Hyeong program, this doesn't return if the program never exits.
+pub fn run_snippet(source: &str, stdin: &[u8]) -> SnippetResult {
+    run_counting_overflows(source, stdin).0
+}
+
+/// Runs `source` like `run_snippet`, and panics if the result could differ between rational
+/// backends.
+///
+/// The backend is chosen at compile time, so the backends can't be run side by side. Instead,
+/// this checks that no arithmetic overflowed the backend in use: fixed-width backends turn
+/// overflowing results into NaN where `big-rational` keeps the exact value, and nothing else
+/// tells the backends apart. Under `big-rational` this never panics.
+pub fn assert_backend_agreement(source: &str, stdin: &[u8]) -> SnippetResult {
+    let (result, overflows) = run_counting_overflows(source, stdin);
+    assert!(
+        overflows == 0,
+        "{} operation(s) overflowed the rational backend; \
+         the result may differ with another backend",
+        overflows
+    );
+    result
+}
+
+fn run_counting_overflows(source: &str, stdin: &[u8]) -> (SnippetResult, u64) {
+    let mut stdout = vec![];
+    let mut stderr = vec![];
+    let (exit_code, overflows) = {
+        let stdin = HyeongReadStack::new(stdin);
+        let stdout = HyeongWriteStack::new(&mut stdout);
+        let stderr = HyeongWriteStack::new(&mut stderr);
+        let stacks = StackManager::from_stacks(stdin, stdout, stderr);
+        let mut processor = Processor::with_stack_manager(Parser::new(source), stacks);
+        let exit_code = processor.run_until(|_| false);
+        let overflows = processor.stack_manager().overflow_count();
+        let (exit_code, err) = (exit_code.unwrap(), processor.stack_manager_mut().flush());
+        // Writing into a `Vec` never fails
+        err.unwrap();
+        (exit_code, overflows)
+    };
+    let result = SnippetResult {
+        stdout,
+        stderr,
+        exit_code,
+    };
+    (result, overflows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{assert_backend_agreement, run_snippet};
+
+    #[test]
+    fn hello_world() {
+        let result = run_snippet(include_str!("../snippets/hello-world.hyeong"), b"");
+        assert_eq!(
+            result.stdout,
+            include_bytes!("../snippets/hello-world.stdout")
+        );
+        assert!(result.stderr.is_empty());
+        assert_eq!(
+            result.exit_code,
+            include!("../snippets/hello-world.exitcode")
+        );
+    }
+
+    #[test]
+    fn stderr() {
+        let result = run_snippet(include_str!("../snippets/stderr.hyeong"), b"");
+        assert!(result.stdout.is_empty());
+        assert_eq!(result.stderr, include_bytes!("../snippets/stderr.stderr"));
+        assert_eq!(result.exit_code, include!("../snippets/stderr.exitcode"));
+    }
+
+    #[test]
+    fn backend_agreement() {
+        let result = assert_backend_agreement(include_str!("../snippets/hello-world.hyeong"), b"");
+        assert_eq!(
+            result,
+            run_snippet(include_str!("../snippets/hello-world.hyeong"), b"")
+        );
+    }
+
+    #[cfg(not(any(feature = "big-rational", feature = "rational-i128")))]
+    #[test]
+    #[should_panic(expected = "overflowed")]
+    fn backend_disagreement() {
+        // Squares 16 four times; 2^64 doesn't fit in an i64
+        let source =
+            "형................ 흑... 하앗... 흑... 하앗... 흑... 하앗... 흑... 하앗... 흑.";
+        assert_backend_agreement(source, b"");
+    }
+}
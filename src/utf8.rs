@@ -1,3 +1,4 @@
+use std::fmt::{self, Display, Formatter};
 use std::io::{self, Read};
 
 const MASK: [u8; 4] = [0x7f, 0x1f, 0x0f, 0x07];
@@ -39,6 +40,54 @@ pub fn read_codepoint<R: Read>(read: &mut R) -> io::Result<u32> {
     Ok(result)
 }
 
+/// An invalid or truncated UTF-8 sequence starting at byte `offset`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DecodeError {
+    pub offset: usize,
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid UTF-8 sequence at byte {}", self.offset)
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes every codepoint in `bytes`, the same way the read stack does.
+pub fn decode_all(bytes: &[u8]) -> Result<Vec<u32>, DecodeError> {
+    let mut result = vec![];
+    let mut read = bytes;
+    while !read.is_empty() {
+        let offset = bytes.len() - read.len();
+        let c = read_codepoint(&mut read).map_err(|_| DecodeError { offset })?;
+        result.push(c);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+#[test]
+fn decode_all_slice() {
+    assert_eq!(decode_all(b""), Ok(vec![]));
+    assert_eq!(
+        decode_all("a¢€𐍈".as_bytes()),
+        Ok(vec![0x61, 0xa2, 0x20ac, 0x10348])
+    );
+    assert_eq!(
+        decode_all(&[0x61, 0xe2, 0x82, 0xac, 0xff, 0x61]),
+        Err(DecodeError { offset: 4 })
+    );
+    assert_eq!(
+        decode_all(&[0x61, 0xe2, 0x41, 0x41]),
+        Err(DecodeError { offset: 1 })
+    );
+    assert_eq!(
+        decode_all(&[0x61, 0xe2, 0x82]),
+        Err(DecodeError { offset: 1 })
+    );
+}
+
 #[cfg(test)]
 #[test]
 fn codepoint_from_slice() {